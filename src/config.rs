@@ -1,30 +1,278 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use itertools::Itertools;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use unicode_segmentation::UnicodeSegmentation;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Aliases {
     pub class: HashMap<String, String>,
     pub instance: HashMap<String, String>,
     pub name: HashMap<String, String>,
+    pub composite: HashMap<String, String>,
 }
 
-#[derive(Deserialize)]
+/// Overlay of general settings and icons, selected by name at load time
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct Profile {
+    pub general: HashMap<String, String>,
+    pub icons: HashMap<String, char>,
+}
+
+/// Events that can trigger renames, all of them unless `trigger_events` is set
+pub const TRIGGER_EVENTS: [&str; 7] = ["new", "close", "move", "title", "empty", "focus", "output"];
+
+/// General settings holding a non-negative integer, a count, length or duration in milliseconds
+const NUMERIC_KEYS: [&str; 13] = [
+    "max_title_length",
+    "collect_depth",
+    "pad_number",
+    "max_label_length",
+    "max_workspaces",
+    "loop_limit",
+    "loop_window_ms",
+    "loop_cooldown_ms",
+    "min_rename_interval_ms",
+    "empty_linger_ms",
+    "move_settle_ms",
+    "name_filter_timeout_ms",
+    "notify_interval_ms",
+];
+
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger_events: Option<Vec<String>>,
     pub icons: HashMap<String, char>,
     pub aliases: Aliases,
     pub general: HashMap<String, String>,
     pub options: HashMap<String, bool>,
+    pub workspace_icons: HashMap<String, String>,
+    pub empty_labels: HashMap<String, String>,
+    pub categories: HashMap<String, String>,
+    pub category_icons: HashMap<String, String>,
+    pub output_icons: HashMap<String, HashMap<String, char>>,
+    pub patterns: HashMap<String, Vec<String>>,
+    pub profiles: HashMap<String, Profile>,
 }
 
 impl Config {
+    /// Validate config values that can't be checked by deserializing alone
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(format) = self.general.get("format") {
+            let tokens = [
+                "num", "split", "titles", "count", "output", "empty", "active", "previous",
+            ];
+            let re = Regex::new(r"\{([^}]*)\}")?;
+            for cap in re.captures_iter(format) {
+                if !tokens.contains(&&cap[1]) {
+                    Err(format!("unknown token {} in format \"{}\"", &cap[0], format))?;
+                }
+            }
+        }
+        // Checked up front, rather than failing on use, or on every event for some
+        for key in NUMERIC_KEYS {
+            if let Some(value) = self.general.get(key) {
+                if value.parse::<u64>().is_err() {
+                    Err(format!("{} = {:?} is not a non-negative integer", key, value))?;
+                }
+            }
+        }
+        // Names are compared ignoring whitespace, so whitespace padding would never be applied
+        if let Some(pad) = self.general.get("pad_char") {
+            if pad.starts_with(char::is_whitespace) {
+                Err(format!("pad_char {:?} can't be whitespace", pad))?;
+            }
+        }
+        // A misspelled event would silently stop renames on the intended one
+        for event in self.trigger_events.iter().flatten() {
+            if !TRIGGER_EVENTS.contains(&event.as_str()) {
+                Err(format!(
+                    "unknown event {:?} in trigger_events, expected one of: {}",
+                    event,
+                    TRIGGER_EVENTS.join(", ")
+                ))?;
+            }
+        }
+        if self.general.contains_key("trigger_events") {
+            Err("trigger_events is a top level list, before any section")?;
+        }
+        // Pattern lists have their own table, so patterns can hold commas
+        for key in ["dialog_classes", "ignore_titles", "exclude_outputs"] {
+            if self.general.contains_key(key) {
+                Err(format!("{} is a list in the [patterns] section", key))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialize the config to TOML, with keys sorted
+    pub fn to_toml(&self) -> Result<String, Box<dyn Error>> {
+        // Going through a toml value sorts the keys of every table
+        let value = toml::Value::try_from(self)?;
+        Ok(toml::to_string(&value)?)
+    }
+
+    /// Warnings for likely mistakes in icons, like values spanning more than one grapheme
+    /// cluster or containing whitespace
+    pub fn warnings(&self) -> Vec<String> {
+        let icons = self
+            .icons
+            .iter()
+            .map(|(k, v)| (format!("icons.{}", k), v.to_string()))
+            .chain(
+                self.workspace_icons
+                    .iter()
+                    .map(|(k, v)| (format!("workspace_icons.{}", k), v.to_owned())),
+            )
+            .chain(
+                self.category_icons
+                    .iter()
+                    .map(|(k, v)| (format!("category_icons.{}", k), v.to_owned())),
+            )
+            .chain(self.output_icons.iter().flat_map(|(output, icons)| {
+                icons
+                    .iter()
+                    .map(move |(k, v)| (format!("output_icons.{}.{}", output, k), v.to_string()))
+            }))
+            .chain(
+                self.general
+                    .get("default_icon")
+                    .map(|v| ("general.default_icon".to_string(), v.to_owned())),
+            );
+
+        let mut warnings = Vec::new();
+        for (key, icon) in icons {
+            if icon.chars().any(char::is_whitespace) {
+                warnings.push(format!("{} = {:?} contains whitespace", key, icon));
+            } else if icon.graphemes(true).count() != 1 {
+                warnings.push(format!("{} = {:?} is not a single glyph", key, icon));
+            }
+        }
+        warnings.extend(self.unaliased_icons());
+        warnings.sort();
+        warnings
+    }
+
+    /// Warnings for icon keys that aren't the value of any alias, likely typos when aliases are
+    /// used. Skipped with `regex_icons`, where keys are patterns, and without aliases, where
+    /// icons can only match window properties
+    fn unaliased_icons(&self) -> Vec<String> {
+        // Template aliases are looked up by their text without the {icon} token
+        let aliases: HashSet<String> = self
+            .aliases
+            .class
+            .values()
+            .chain(self.aliases.instance.values())
+            .chain(self.aliases.name.values())
+            .chain(self.aliases.composite.values())
+            .map(|alias| alias.replace("{icon}", " ").split_whitespace().join(" "))
+            .collect();
+        if aliases.is_empty() || self.options.get("regex_icons").copied().unwrap_or(false) {
+            return Vec::new();
+        }
+        self.icons
+            .keys()
+            .filter(|key| !aliases.contains(*key))
+            .map(|key| {
+                format!(
+                    "icons.{} is not the value of any alias, and only matches a window property \
+                     of exactly {:?}",
+                    key, key
+                )
+            })
+            .collect()
+    }
+
+    /// Merge another config on top of this one, per section
+    pub fn merge(&mut self, other: Config) {
+        self.icons.extend(other.icons);
+        self.aliases.class.extend(other.aliases.class);
+        self.aliases.instance.extend(other.aliases.instance);
+        self.aliases.name.extend(other.aliases.name);
+        self.aliases.composite.extend(other.aliases.composite);
+        self.general.extend(other.general);
+        self.options.extend(other.options);
+        self.workspace_icons.extend(other.workspace_icons);
+        self.empty_labels.extend(other.empty_labels);
+        self.categories.extend(other.categories);
+        self.category_icons.extend(other.category_icons);
+        for (output, icons) in other.output_icons {
+            self.output_icons.entry(output).or_default().extend(icons);
+        }
+        self.patterns.extend(other.patterns);
+        if other.trigger_events.is_some() {
+            self.trigger_events = other.trigger_events;
+        }
+        self.profiles.extend(other.profiles);
+    }
+
+    /// Merge all config fragments in a directory on top of this config, in lexical order
+    pub fn merge_dir(&mut self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .and_then(|ext| ext.to_str())
+                    .map_or(false, |ext| EXTENSIONS.contains(&ext))
+            })
+            .collect();
+        paths.sort();
+        for path in paths {
+            let fragment = read_config(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            self.merge(fragment);
+        }
+        Ok(())
+    }
+
+    /// Overlay the named profile onto general settings and icons
+    pub fn apply_profile(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let profile = self
+            .profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("profile \"{}\" not found", name))?;
+        self.general.extend(profile.general);
+        self.icons.extend(profile.icons);
+        Ok(())
+    }
+
+    /// Merge the shared preset named by `general.preset` beneath this config, searching
+    /// `$XDG_DATA_HOME` then `$XDG_DATA_DIRS` for `i3wsr/presets/<name>.toml`
+    pub fn merge_preset(&mut self) -> Result<(), Box<dyn Error>> {
+        let name = match self.general.get("preset") {
+            Some(name) => name.to_owned(),
+            None => return Ok(()),
+        };
+        let paths: Vec<PathBuf> = preset_dirs()
+            .into_iter()
+            .map(|dir| dir.join("i3wsr/presets").join(format!("{}.toml", name)))
+            .collect();
+        let path = match paths.iter().find(|path| path.is_file()) {
+            Some(path) => path,
+            None => Err(format!(
+                "preset \"{}\" not found, searched: {}",
+                name,
+                paths.iter().map(|path| path.display().to_string()).join(", ")
+            ))?,
+        };
+        let mut preset = read_config(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        preset.merge(self.clone());
+        *self = preset;
+        Ok(())
+    }
+
     pub fn new(filename: &Path, icons_override: &str) -> Result<Self, Box<dyn Error>> {
-        let file_config = read_toml_config(filename)?;
+        let file_config = read_config(filename)?;
         Ok(Config {
             icons: file_config
                 .icons
@@ -42,6 +290,7 @@ impl Default for Aliases {
             class: HashMap::new(),
             instance: HashMap::new(),
             name: HashMap::new(),
+            composite: HashMap::new(),
         }
     }
 }
@@ -49,22 +298,56 @@ impl Default for Aliases {
 impl Default for Config {
     fn default() -> Self {
         Config {
+            trigger_events: None,
             icons: HashMap::new(),
             aliases: Aliases {
                 class: HashMap::new(),
                 instance: HashMap::new(),
                 name: HashMap::new(),
+                composite: HashMap::new(),
             },
             general: HashMap::new(),
             options: HashMap::new(),
+            workspace_icons: HashMap::new(),
+            empty_labels: HashMap::new(),
+            categories: HashMap::new(),
+            category_icons: HashMap::new(),
+            output_icons: HashMap::new(),
+            patterns: HashMap::new(),
+            profiles: HashMap::new(),
         }
     }
 }
 
-fn read_toml_config(filename: &Path) -> Result<Config, Box<dyn Error>> {
+/// Data dirs searched for presets, in order of precedence
+fn preset_dirs() -> Vec<PathBuf> {
+    let data_dirs = env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+    dirs::data_dir()
+        .into_iter()
+        .chain(env::split_paths(&data_dirs))
+        .collect()
+}
+
+/// Config file extensions, the format is picked by extension
+const EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+fn read_config(filename: &Path) -> Result<Config, Box<dyn Error>> {
     let mut file = File::open(filename)?;
     let mut buffer = String::new();
     file.read_to_string(&mut buffer)?;
-    let config: Config = toml::from_str(&buffer)?;
+    let config: Config = match filename.extension().and_then(|ext| ext.to_str()) {
+        #[cfg(feature = "yaml")]
+        Some("yaml" | "yml") => serde_yaml::from_str(&buffer)?,
+        #[cfg(not(feature = "yaml"))]
+        Some("yaml" | "yml") => Err("YAML config requires building with the yaml feature")?,
+        #[cfg(feature = "json")]
+        Some("json") => serde_json::from_str(&buffer)?,
+        #[cfg(not(feature = "json"))]
+        Some("json") => Err("JSON config requires building with the json feature")?,
+        _ => toml::from_str(&buffer)?,
+    };
     Ok(config)
 }
@@ -3,18 +3,52 @@ use i3ipc::{
         inner::{WindowChange, WorkspaceChange},
         WindowEventInfo, WorkspaceEventInfo,
     },
-    reply::{Node, NodeType, WindowProperty},
+    reply::{Node, NodeLayout, NodeType, WindowProperty},
     I3Connection,
 };
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use std::time::{Duration, Instant};
 
 pub mod config;
 pub mod icons;
 pub mod regex;
+pub mod state;
 use config::Config;
+use state::State;
 use std::error::Error;
 
+/// Print extra information, like renames, set by the `--verbose` flag
+pub static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Suppress informational output, set by the `--quiet` flag
+pub static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Maximum depth when recursing the tree, a sane tree is nowhere near this deep
+const MAX_DEPTH: usize = 64;
+
+/// Check if recursion has gone past max depth, warning when verbose
+fn exceeds_depth(depth: usize) -> bool {
+    if depth > MAX_DEPTH {
+        if VERBOSE.load(Ordering::Relaxed) {
+            eprintln!("max tree depth of {} exceeded, stopping traversal", MAX_DEPTH);
+        }
+        return true;
+    }
+    false
+}
+
+/// Maximum number of times an alias is fed back into class aliases when chaining
+const MAX_ALIAS_CHAIN: usize = 4;
+
 /// Helper fn to get options via config
 fn get_option(config: &Config, key: &str) -> bool {
     return match config.options.get(key) {
@@ -23,11 +57,60 @@ fn get_option(config: &Config, key: &str) -> bool {
     };
 }
 
+/// Helper fn to get a duration in milliseconds via config
+fn get_duration(config: &Config, key: &str) -> Result<Option<Duration>, Box<dyn Error>> {
+    match config.general.get(key) {
+        Some(ms) => Ok(Some(Duration::from_millis(ms.parse()?))),
+        None => Ok(None),
+    }
+}
+
+/// Find an alias for value, either the first matching pattern, or with `alias_match = "longest"`
+/// the pattern with the longest match. Patterns come from a map in no particular order, so equally
+/// long matches go to the lowest sorting pattern
+fn find_alias<'a>(
+    value: &str,
+    patterns: &'a [regex::Point],
+    config: &Config,
+) -> Option<&'a String> {
+    match config.general.get("alias_match").map(|s| s.as_str()) {
+        Some("longest") => patterns
+            .iter()
+            .filter_map(|(re, alias)| re.find(value).map(|m| (m.len(), re.as_str(), alias)))
+            .max_by(|(a, a_re, _), (b, b_re, _)| a.cmp(b).then_with(|| b_re.cmp(a_re)))
+            .map(|(_, _, alias)| alias),
+        _ => patterns
+            .iter()
+            .find(|(re, _)| re.is_match(value))
+            .map(|(_, alias)| alias),
+    }
+}
+
+/// A resolved window title, whether it came from an alias, the key used for grouping, if its
+/// window has focus, the template to render it with if its alias is one, and whether it's a
+/// placeholder for a window without a title
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Title {
+    pub name: String,
+    pub aliased: bool,
+    pub key: String,
+    pub focused: bool,
+    pub template: Option<String>,
+    pub placeholder: bool,
+}
+
+/// A formatted title, with the key of the title it was formatted from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Segment {
+    key: String,
+    text: String,
+}
+
 fn get_title(
     props: &HashMap<WindowProperty, String>,
     config: &Config,
     res: &regex::Compiled,
-) -> Result<String, Box<dyn Error>> {
+) -> Result<Title, Box<dyn Error>> {
     let wm_class = props.get(&WindowProperty::Class);
     let wm_instance = props.get(&WindowProperty::Instance);
     let wm_name = props.get(&WindowProperty::Title);
@@ -36,30 +119,46 @@ fn get_title(
         None => "class",
     };
 
+    // Composite aliases match class and instance together, as "{class}/{instance}"
+    let composite = match (wm_class, wm_instance) {
+        (Some(class), Some(instance)) => Some(format!("{}/{}", class, instance)),
+        _ => None,
+    };
+
     // Check for aliases using pre-compiled regex
-    let title = {
-        if let Some((_, alias)) =
-            wm_name.and_then(|name| res.name.iter().filter(|(re, _)| re.is_match(&name)).next())
-        {
-            alias
-        } else if let Some((_, alias)) = wm_instance.and_then(|instance| {
-            res.instance
-                .iter()
-                .filter(|(re, _)| re.is_match(&instance))
-                .next()
-        }) {
-            alias
-        } else if let Some((_, alias)) = wm_class.and_then(|class| {
-            res.class
-                .iter()
-                .filter(|(re, _)| re.is_match(&class))
-                .next()
-        }) {
-            alias
-        } else {
+    let alias = composite
+        .as_deref()
+        .and_then(|composite| find_alias(composite, &res.composite, config))
+        .or_else(|| wm_name.and_then(|name| find_alias(name, &res.name, config)))
+        .or_else(|| wm_instance.and_then(|instance| find_alias(instance, &res.instance, config)))
+        .or_else(|| wm_class.and_then(|class| find_alias(class, &res.class, config)))
+        .or(res.catch_all.as_ref());
+
+    // Optionally feed the alias back into class aliases, capped to guard against loops
+    let alias = match alias {
+        Some(mut alias) if get_option(&config, "chain_aliases") => {
+            for _ in 0..MAX_ALIAS_CHAIN {
+                match find_alias(alias, &res.class, config) {
+                    Some(next) if next != alias => alias = next,
+                    _ => break,
+                }
+            }
+            Some(alias)
+        }
+        alias => alias,
+    };
+
+    // Unaliased windows can be hidden, leaving only aliased windows in the name
+    let hidden = String::new();
+    let title = match alias {
+        Some(alias) => alias,
+        None if get_option(&config, "aliased_only") => &hidden,
+        None => {
             // Handle display prop, if no alias is located, then check for existiance and
             // display_prop to set a fallback title
-            if wm_name.is_some() && display_prop == "name" {
+            // Volatile names can optionally be restricted to alias matching only
+            let name_alias_only = get_option(&config, "name_alias_only");
+            if wm_name.is_some() && display_prop == "name" && !name_alias_only {
                 wm_name.unwrap()
             } else if wm_instance.is_some() && display_prop == "instance"  {
                 wm_instance.unwrap()
@@ -74,35 +173,322 @@ fn get_title(
         }
     };
 
-    let no_names = get_option(&config, "no_names");
-    let no_icon_names = get_option(&config, "no_icon_names");
+    let key = wm_class.unwrap_or(title).to_owned();
+    let title = if get_option(&config, "ascii_fallback") {
+        to_ascii(title)
+    } else {
+        title.to_owned()
+    };
+
+    let (name, template) = parse_template(title);
 
-    // Format final result
-    Ok(match config.icons.get(title) {
-        Some(icon) => {
-            if no_icon_names || no_names {
-                format!("{}", icon)
+    Ok(Title {
+        name,
+        aliased: alias.is_some(),
+        key,
+        focused: false,
+        template,
+        placeholder: false,
+    })
+}
+
+/// Split a title into its name and template. Aliases containing {icon} are templates, rendered as
+/// is with the icon in place. The text without the token is the name used to look up icons and
+/// group titles
+fn parse_template(title: String) -> (String, Option<String>) {
+    if title.contains("{icon}") {
+        let name = title.replace("{icon}", " ").split_whitespace().join(" ");
+        (name, Some(title))
+    } else {
+        (title, None)
+    }
+}
+
+/// Best effort ASCII transliteration, decomposing characters and dropping whatever isn't ASCII,
+/// like accents and emoji, along with the whitespace left around them. Text without anything
+/// to transliterate, like CJK, is kept as is rather than leaving the window out
+fn to_ascii(text: &str) -> String {
+    let ascii: String = text.nfd().filter(char::is_ascii).collect();
+    match ascii.split_whitespace().join(" ") {
+        ascii if ascii.is_empty() => text.to_owned(),
+        ascii => ascii,
+    }
+}
+
+/// Format a count as a suffix, either using superscript digits or plain
+fn format_count(count: usize, config: &Config) -> String {
+    match config.general.get("icon_count_suffix").map(|s| s.as_str()) {
+        Some("superscript") => count
+            .to_string()
+            .chars()
+            .map(|c| match c {
+                '0' => '⁰',
+                '1' => '¹',
+                '2' => '²',
+                '3' => '³',
+                '4' => '⁴',
+                '5' => '⁵',
+                '6' => '⁶',
+                '7' => '⁷',
+                '8' => '⁸',
+                '9' => '⁹',
+                _ => c,
+            })
+            .collect(),
+        Some("plain") => count.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Get the icon for a title, preferring icons set for the workspace's output, falling back to
+/// default icon. With `regex_icons` all matching icons are joined by `icon_separator`
+fn get_icon(
+    title: &Title,
+    output: &str,
+    config: &Config,
+    res: &regex::Compiled,
+) -> Option<String> {
+    let output_icon = config
+        .output_icons
+        .get(output)
+        .and_then(|icons| icons.get(&title.name));
+    let icon = if let Some(icon) = output_icon {
+        Some(icon.to_string())
+    } else if get_option(&config, "regex_icons") {
+        let separator = config.general.get("icon_separator").map_or("", |s| s.as_str());
+        let icons: Vec<String> = res
+            .icons
+            .iter()
+            .filter(|(re, _)| re.is_match(&title.name))
+            .map(|(_, icon)| icon.to_string())
+            .collect();
+        if icons.is_empty() {
+            None
+        } else {
+            Some(icons.join(separator))
+        }
+    } else {
+        config.icons.get(&title.name).map(|icon| icon.to_string())
+    };
+
+    match icon {
+        Some(icon) => Some(icon),
+        None => {
+            // Never put the default icon on an empty title or a placeholder, and optionally only
+            // on aliased titles
+            if title.name.is_empty()
+                || title.placeholder
+                || (get_option(&config, "default_icon_for_aliases_only") && !title.aliased)
+            {
+                None
             } else {
-                format!("{} {}", icon, title)
+                config.general.get("default_icon").cloned()
             }
         }
-        None => match config.general.get("default_icon") {
-            Some(default_icon) => {
-                if no_icon_names || no_names {
-                    format!("{}", default_icon)
-                } else {
-                    format!("{} {}", default_icon, title)
-                }
+    }
+}
+
+/// Truncate title to max grapheme clusters, keeping the head, tail or middle depending on mode,
+/// and marking the elided part with an ellipsis. Works on graphemes so composite emoji, like flags
+/// and ZWJ sequences, are kept whole
+fn truncate_title(title: &str, max: usize, mode: &str) -> String {
+    let graphemes: Vec<&str> = title.graphemes(true).collect();
+    if graphemes.len() <= max {
+        return title.to_string();
+    }
+    match mode {
+        // Nothing fits, not even an ellipsis
+        _ if max == 0 => String::new(),
+        "tail" => {
+            let keep = max.saturating_sub(1);
+            format!("…{}", graphemes[graphemes.len() - keep..].concat())
+        }
+        // Too short to keep anything between two ellipses, cut like head instead
+        "middle" if max >= 3 => {
+            let keep = max - 2;
+            let start = (graphemes.len() - keep) / 2;
+            format!("…{}…", graphemes[start..start + keep].concat())
+        }
+        _ => {
+            let keep = max.saturating_sub(1);
+            format!("{}…", graphemes[..keep].concat())
+        }
+    }
+}
+
+/// Format title with icon according to options, count is the number of collapsed duplicates
+/// and is only displayed in icon only mode
+fn format_with_icon(title: &Title, icon: Option<&str>, count: usize, config: &Config) -> String {
+    // Icons likely render as tofu where only ASCII is supported
+    let icon = icon.filter(|_| !get_option(&config, "ascii_fallback"));
+
+    // The focused window can optionally show its name regardless of name suppression
+    let show_name = title.focused && get_option(&config, "show_focused_name");
+    let no_names = get_option(&config, "no_names") && !show_name;
+    let no_icon_names = get_option(&config, "no_icon_names") && !show_name;
+    let suffix = if count > 1 {
+        format_count(count, config)
+    } else {
+        String::new()
+    };
+
+    // Truncate displayed name only, so icons still match on the full title
+    let name = match config
+        .general
+        .get("max_title_length")
+        .and_then(|max| max.parse().ok())
+    {
+        Some(max) => {
+            let mode = config.general.get("truncate_mode").map_or("head", |s| s.as_str());
+            truncate_title(&title.name, max, mode)
+        }
+        None => title.name.to_owned(),
+    };
+
+    match (icon, &title.template) {
+        (Some(icon), _) if no_icon_names || no_names => format!("{}{}", icon, suffix),
+        (icon, Some(template)) if !no_names => {
+            template.replace("{icon}", icon.unwrap_or("")).trim().to_string()
+        }
+        (Some(icon), _) => format!("{} {}", icon, name),
+        (None, _) => {
+            if no_names {
+                String::new()
+            } else {
+                format!("{}", name)
             }
-            None => {
-                if no_names {
-                    String::new()
-                } else {
-                    format!("{}", title)
+        }
+    }
+}
+
+/// Render one segment per distinct category present, in order of first appearance, using the
+/// category icon or else the category name. Titles without a category are formatted as usual
+fn format_categories(
+    titles: &[Title],
+    output: &str,
+    config: &Config,
+    res: &regex::Compiled,
+) -> Vec<Segment> {
+    let mut seen = HashSet::new();
+    let mut segments = Vec::new();
+    let mut rest = Vec::new();
+    for title in titles {
+        match config.categories.get(&title.name) {
+            Some(category) => {
+                if seen.insert(category) {
+                    segments.push(Segment {
+                        key: category.to_owned(),
+                        text: config
+                            .category_icons
+                            .get(category)
+                            .unwrap_or(category)
+                            .to_owned(),
+                    });
                 }
             }
-        },
-    })
+            None => rest.push(title.to_owned()),
+        }
+    }
+    segments.extend(format_titles(&rest, output, config, res));
+    segments
+}
+
+/// Append a group count to a title using `group_count_format`, optionally omitting it for single
+/// windows, and never for hidden titles
+fn format_group_count(text: String, count: usize, config: &Config) -> String {
+    if text.is_empty() || (count == 1 && get_option(&config, "group_count_omit_single")) {
+        return text;
+    }
+    let format = config
+        .general
+        .get("group_count_format")
+        .map_or("({count})", |s| s.as_str());
+    text + &format.replace("{count}", &count.to_string())
+}
+
+/// Format a workspace's titles according to options, output is the name of the workspace's output
+fn format_titles(
+    titles: &[Title],
+    output: &str,
+    config: &Config,
+    res: &regex::Compiled,
+) -> Vec<Segment> {
+    // Collapse duplicates by name keeping count, so it can be passed to formatting. Focus, key
+    // and whether the name came from an alias don't make a window a different title, though a
+    // focused duplicate is kept so it can still show as focused
+    let remove_duplicates = get_option(&config, "remove_duplicates");
+    // Or group titles by name, counting the windows resolved to each
+    let count_groups = get_option(&config, "count_groups");
+    let counts = titles.iter().map(|title| &title.name).counts();
+    let titles: Vec<(&Title, usize)> = if count_groups {
+        titles
+            .iter()
+            .unique_by(|title| &title.name)
+            .map(|title| (title, counts[&title.name]))
+            .collect()
+    } else if remove_duplicates {
+        titles
+            .iter()
+            .unique_by(|title| &title.name)
+            .map(|title| {
+                let focused = titles.iter().find(|t| t.focused && t.name == title.name);
+                (focused.unwrap_or(title), counts[&title.name])
+            })
+            .collect()
+    } else {
+        titles.iter().map(|title| (title, 1)).collect()
+    };
+
+    // Icons are only deduplicated next to names, without names a window would lose its icon and
+    // fall back to showing its name
+    let dedupe_icons = get_option(&config, "dedupe_icons")
+        && !get_option(&config, "no_icon_names")
+        && !get_option(&config, "no_names");
+    let mut seen_icons = HashSet::new();
+    let mut formatted = Vec::new();
+    for (title, count) in titles {
+        let icon = match get_icon(title, output, config, res) {
+            // Only show an icon on its first occurrence
+            Some(icon) if dedupe_icons && !seen_icons.insert(icon.clone()) => None,
+            icon => icon,
+        };
+        let text = if count_groups {
+            let text = format_with_icon(title, icon.as_deref(), 1, config);
+            format_group_count(text, count, config)
+        } else {
+            format_with_icon(title, icon.as_deref(), count, config)
+        };
+        formatted.push(Segment {
+            key: title.key.to_owned(),
+            text,
+        });
+    }
+
+    let formatted = if remove_duplicates {
+        formatted
+            .into_iter()
+            .unique_by(|segment| segment.text.to_owned())
+            .collect()
+    } else {
+        formatted
+    };
+    // Remove empty titles, either from no_names or aliases with an empty replacement
+    let formatted = formatted
+        .into_iter()
+        .filter(|segment| !segment.text.is_empty());
+
+    // Prefix titles with their 1-based index in the workspace
+    if get_option(&config, "number_windows") {
+        formatted
+            .enumerate()
+            .map(|(i, segment)| Segment {
+                text: format!("{}:{}", i + 1, segment.text),
+                ..segment
+            })
+            .collect()
+    } else {
+        formatted.collect()
+    }
 }
 
 /// return a collection of workspace nodes
@@ -129,262 +515,2529 @@ fn get_workspaces(tree: Node) -> Vec<Node> {
     out
 }
 
-/// get window ids for any depth collection of nodes
-fn get_properties(mut nodes: Vec<Vec<&Node>>) -> Vec<HashMap<WindowProperty, String>> {
-    let mut window_props = Vec::new();
+/// Map workspace ids to the name of the output they're on
+fn get_workspace_outputs(tree: &Node) -> HashMap<i64, String> {
+    let mut out = HashMap::new();
 
-    while let Some(next) = nodes.pop() {
-        for n in next {
-            nodes.push(n.nodes.iter().collect());
-            if let Some(w) = &n.window_properties {
-                window_props.push(w.to_owned());
+    for output in &tree.nodes {
+        for container in &output.nodes {
+            for workspace in &container.nodes {
+                if let (NodeType::Workspace, Some(name)) = (&workspace.nodetype, &output.name) {
+                    out.insert(workspace.id, name.to_owned());
+                }
             }
         }
     }
 
-    window_props
+    out
+}
+
+/// Get the name of the focused output, the one containing the focused workspace
+fn get_focused_output(tree: &Node) -> Option<String> {
+    tree.nodes
+        .iter()
+        .find(|output| is_focused(output, 0))
+        .and_then(|output| output.name.to_owned())
 }
 
-/// Collect a vector of workspace titles
-fn collect_titles(workspace: &Node, config: &Config, res: &regex::Compiled) -> Vec<String> {
-    let window_props = {
-        let mut f = get_properties(vec![workspace.floating_nodes.iter().collect()]);
-        let mut n = get_properties(vec![workspace.nodes.iter().collect()]);
-        n.append(&mut f);
-        n
+/// Find the name of the workspace containing a node with given id
+fn find_workspace_name(
+    node: &Node,
+    id: i64,
+    workspace: Option<&str>,
+    depth: usize,
+) -> Option<String> {
+    if exceeds_depth(depth) {
+        return None;
+    }
+    let workspace = match node.nodetype {
+        NodeType::Workspace => node.name.as_deref(),
+        _ => workspace,
     };
-
-    let mut titles = Vec::new();
-    for props in window_props {
-        let title = match get_title(&props, config, res) {
-            Ok(title) => title,
-            Err(e) => {
-                eprintln!("get_title error: \"{}\" for workspace {:#?}", e, workspace);
-                continue;
-            }
-        };
-        titles.push(title);
+    if node.id == id {
+        return workspace.map(String::from);
     }
+    node.nodes
+        .iter()
+        .chain(node.floating_nodes.iter())
+        .find_map(|n| find_workspace_name(n, id, workspace, depth + 1))
+}
 
-    titles
+/// Check if a window event is a scratchpad window being shown, given the name of the workspace
+/// the window is on after the event. Hiding a window moves it to `__i3_scratch`, which is
+/// remembered and renames the workspace it left as usual, showing it again is transient and
+/// skipped to avoid flicker. Only the first move after hiding is a show, later moves are real
+fn is_scratchpad_show(
+    change: &WindowChange,
+    id: i64,
+    workspace: Option<&str>,
+    state: &mut State,
+) -> bool {
+    match change {
+        WindowChange::Close => {
+            state.scratchpad.remove(&id);
+            false
+        }
+        WindowChange::Move if workspace == Some("__i3_scratch") => {
+            state.scratchpad.insert(id);
+            false
+        }
+        WindowChange::Move => state.scratchpad.remove(&id),
+        _ => false,
+    }
 }
 
-/// Update all workspace names in tree
-pub fn update_tree(
-    i3_conn: &mut I3Connection,
-    config: &Config,
-    res: &regex::Compiled,
-) -> Result<(), Box<dyn Error>> {
-    let tree = i3_conn.get_tree()?;
-    for workspace in get_workspaces(tree) {
-        let separator = match config.general.get("separator") {
-            Some(s) => s,
-            None => " | ",
-        };
+/// get window nodes for any depth collection of nodes, optionally descending no deeper than
+/// max depth, where depth 1 is the given nodes
+fn get_windows(nodes: Vec<Vec<&Node>>, max_depth: Option<usize>) -> Vec<&Node> {
+    let mut windows = Vec::new();
+    let mut nodes: Vec<(usize, Vec<&Node>)> = nodes.into_iter().map(|n| (1, n)).collect();
 
-        let titles = collect_titles(&workspace, config, res);
-        let titles = if get_option(&config, "remove_duplicates") {
-            titles.into_iter().unique().collect()
-        } else {
-            titles
-        };
-        let titles = if get_option(&config, "no_names") {
-            titles
-                .into_iter()
-                .filter(|s| !s.is_empty())
-                .collect::<Vec<String>>()
-        } else {
-            titles
-        };
-        let titles = titles.join(separator);
-        let titles = if !titles.is_empty() {
-            format!(" {}", titles)
-        } else {
-            titles
-        };
-        let old: String = workspace.name.to_owned().ok_or_else(|| {
-            format!(
-                "Failed to get workspace name for workspace: {:#?}",
-                workspace
-            )
-        })?;
-
-        // Get split_at arg
-        let split_at = match config.general.get("split_at") {
-            Some(s) => {
-                if !s.is_empty() {
-                    s.chars().next().unwrap()
-                } else {
-                    ' '
-                }
+    while let Some((depth, next)) = nodes.pop() {
+        for n in next {
+            if max_depth.map_or(true, |max| depth < max) {
+                nodes.push((depth + 1, n.nodes.iter().collect()));
             }
-            None => ' ',
-        };
+            if n.window_properties.is_some() {
+                windows.push(n);
+            }
+        }
+    }
 
-        // Get the initial element we want to keep
-        let initial = match old.split(split_at).next() {
-            Some(i) => i,
-            None => "",
-        };
+    windows
+}
 
-        let mut new: String = String::from(initial);
+/// Get a workspace's window nodes, tiled windows first, then floating. i3 only attaches floating
+/// containers at the workspace level, so nested containers are only followed through their tiled
+/// nodes, and each window is collected exactly once. With a max depth, 1 is the workspace's direct
+/// children, counting floating windows as direct children
+fn get_workspace_windows(workspace: &Node, max_depth: Option<usize>) -> Vec<&Node> {
+    let mut windows = get_windows(vec![workspace.nodes.iter().collect()], max_depth);
+    let floating = vec![workspace.floating_nodes.iter().collect()];
+    windows.append(&mut get_windows(floating, max_depth.map(|max| max + 1)));
+    windows
+}
 
-        // if we do split on colon we need to insert a new one, cause it gets split out
-        if split_at == ':' && !initial.is_empty() && !titles.is_empty() {
-            new.push(':');
-        }
-        // Push new window titles to new string
-        if !titles.is_empty() {
-            new.push_str(&titles);
-        }
+/// Map window ids to window properties for any depth collection of nodes
+fn get_window_map(
+    mut nodes: Vec<Vec<&Node>>,
+) -> HashMap<String, &HashMap<WindowProperty, String>> {
+    let mut window_map = HashMap::new();
 
-        if titles.is_empty() {
-            match config.general.get("empty_label") {
-                Some(default_label) => {
-                    new.push_str(" ");
-                    new.push_str(default_label);
-                }
-                None => (),
+    while let Some(next) = nodes.pop() {
+        for n in next {
+            nodes.push(n.nodes.iter().chain(n.floating_nodes.iter()).collect());
+            if let (Some(id), Some(w)) = (n.window, &n.window_properties) {
+                window_map.insert(id.to_string(), w);
             }
         }
+    }
+
+    window_map
+}
+
+/// Resolve the properties of a dialog's parent window, using transient for. This is best effort,
+/// and falls back to the dialogs own properties if the parent can't be found on the workspace
+fn resolve_parent<'a>(
+    props: &'a HashMap<WindowProperty, String>,
+    window_map: &HashMap<String, &'a HashMap<WindowProperty, String>>,
+    res: &regex::Compiled,
+) -> &'a HashMap<WindowProperty, String> {
+    let is_dialog = props
+        .get(&WindowProperty::Class)
+        .map_or(false, |class| res.dialog.iter().any(|re| re.is_match(class)));
+    if !is_dialog {
+        return props;
+    }
+    match props
+        .get(&WindowProperty::TransientFor)
+        .and_then(|parent| window_map.get(parent))
+    {
+        Some(parent) => *parent,
+        None => props,
+    }
+}
 
-        // Dispatch to i3
-        if old != new {
-            let command = format!("rename workspace \"{}\" to \"{}\"", old, new);
-            i3_conn.run_command(&command)?;
+/// Collect window ids ordered by focus recency, following each container's focus list depth first
+fn focus_order(node: &Node, order: &mut Vec<i64>, depth: usize) {
+    if exceeds_depth(depth) {
+        return;
+    }
+    for id in &node.focus {
+        let child = node
+            .nodes
+            .iter()
+            .chain(node.floating_nodes.iter())
+            .find(|child| child.id == *id);
+        if let Some(child) = child {
+            if child.window_properties.is_some() {
+                order.push(child.id);
+            }
+            focus_order(child, order, depth + 1);
         }
     }
-    Ok(())
 }
 
-/// handles new and close window events, to set the workspace name based on content
-pub fn handle_window_event(
-    e: &WindowEventInfo,
-    i3_conn: &mut I3Connection,
-    config: &Config,
-    res: &regex::Compiled,
-) -> Result<(), Box<dyn Error>> {
-    match e.change {
-        WindowChange::New | WindowChange::Close | WindowChange::Move | WindowChange::Title => {
-            update_tree(i3_conn, config, res)?;
+/// Collect ids of windows hidden behind the focused tab of tabbed or stacked containers, falling
+/// back to showing the first tab if focus is unknown
+fn hidden_tabs(node: &Node, hidden: &mut HashSet<i64>, depth: usize) {
+    if exceeds_depth(depth) {
+        return;
+    }
+    if let NodeLayout::Tabbed | NodeLayout::Stacked = node.layout {
+        let windows = get_windows(vec![node.nodes.iter().collect()], None);
+        let mut order = Vec::new();
+        focus_order(node, &mut order, depth);
+        let shown = order.first().or(windows.first().map(|window| &window.id));
+        for window in &windows {
+            if shown != Some(&window.id) {
+                hidden.insert(window.id);
+            }
         }
-        _ => (),
+        return;
+    }
+    for child in &node.nodes {
+        hidden_tabs(child, hidden, depth + 1);
     }
-    Ok(())
 }
 
-/// handles ws events,
-pub fn handle_ws_event(
-    e: &WorkspaceEventInfo,
-    i3_conn: &mut I3Connection,
+/// Collect a vector of workspace titles, windows where no title is found are skipped and their
+/// errors returned alongside the titles, or shown as a placeholder per `on_missing_title`
+fn collect_titles(
+    workspace: &Node,
     config: &Config,
     res: &regex::Compiled,
-) -> Result<(), Box<dyn Error>> {
-    match e.change {
-        WorkspaceChange::Empty | WorkspaceChange::Focus => {
-            update_tree(i3_conn, config, res)?;
+) -> (Vec<Title>, Vec<Box<dyn Error>>) {
+    let max_depth = config
+        .general
+        .get("collect_depth")
+        .and_then(|depth| depth.parse().ok());
+    let mut windows = get_workspace_windows(workspace, max_depth);
+
+    // Most recently focused first, keeping tree order if focus info is incomplete
+    if config.general.get("order_by").map(|s| s.as_str()) == Some("focus") {
+        let mut order = Vec::new();
+        focus_order(workspace, &mut order, 0);
+        if windows.iter().all(|window| order.contains(&window.id)) {
+            windows.sort_by_key(|window| order.iter().position(|id| *id == window.id));
         }
-        _ => (),
     }
-    Ok(())
-}
 
-#[cfg(test)]
-mod tests {
-    use i3ipc::reply::{NodeType, WindowProperty};
-    use std::collections::HashMap;
-    use std::env;
-    use std::error::Error;
+    // Tabbed and stacked containers only contribute their focused tab
+    if get_option(&config, "collapse_tabbed") {
+        let mut hidden = HashSet::new();
+        for child in &workspace.nodes {
+            hidden_tabs(child, &mut hidden, 1);
+        }
+        windows.retain(|window| !hidden.contains(&window.id));
+    }
 
-    #[test]
-    fn connection_tree() -> Result<(), Box<dyn Error>> {
-        env::set_var("DISPLAY", ":99.0");
-        let mut i3_conn = super::I3Connection::connect()?;
-        let config = super::Config::default();
-        let res = super::regex::parse_config(&config)?;
-        assert!(super::update_tree(&mut i3_conn, &config, &res).is_ok());
-        let tree = i3_conn.get_tree()?;
-        let mut name: String = String::new();
-        for output in &tree.nodes {
-            for container in &output.nodes {
-                for workspace in &container.nodes {
-                    if let NodeType::Workspace = workspace.nodetype {
-                        let ws_n = workspace.name.to_owned();
-                        name = ws_n.unwrap();
-                    }
-                }
+    // Only the focused window, or the last focused one on unfocused workspaces
+    if get_option(&config, "focused_window_only") {
+        let mut order = Vec::new();
+        focus_order(workspace, &mut order, 0);
+        windows.retain(|window| order.first() == Some(&window.id));
+    }
+
+    let window_map = if get_option(&config, "inherit_parent") {
+        get_window_map(vec![workspace
+            .nodes
+            .iter()
+            .chain(workspace.floating_nodes.iter())
+            .collect()])
+    } else {
+        HashMap::new()
+    };
+
+    let ignored = |title: &str| res.ignore_titles.iter().any(|re| re.is_match(title));
+    let mut titles = Vec::new();
+    let mut resolved = Vec::new();
+    let mut errors = Vec::new();
+    for window in windows {
+        let props = match &window.window_properties {
+            Some(props) => resolve_parent(props, &window_map, res),
+            None => continue,
+        };
+        // Skip ephemeral windows by their raw window title, or resolved name
+        if props.get(&WindowProperty::Title).map_or(false, |title| ignored(title)) {
+            continue;
+        }
+        match get_title(&props, config, res) {
+            Ok(title) if ignored(&title.name) => continue,
+            Ok(title) => {
+                titles.push(Title {
+                    focused: window.focused,
+                    ..title
+                });
+                resolved.push(props);
             }
+            Err(e) => match config.general.get("on_missing_title").map(|s| s.as_str()) {
+                Some("placeholder") => {
+                    let placeholder = config
+                        .general
+                        .get("missing_title_placeholder")
+                        .map_or("?", |s| s.as_str());
+                    titles.push(Title {
+                        name: placeholder.to_owned(),
+                        aliased: false,
+                        key: placeholder.to_owned(),
+                        focused: window.focused,
+                        template: None,
+                        placeholder: true,
+                    });
+                    resolved.push(props);
+                }
+                _ => errors.push(e),
+            },
         }
-        assert_eq!(name, String::from("1 Gpick | XTerm"));
-        Ok(())
     }
+    disambiguate(&mut titles, &resolved, config, res);
 
-    #[test]
-    fn get_title() -> Result<(), Box<dyn Error>> {
-        env::set_var("DISPLAY", ":99.0");
-        let mut i3_conn = super::I3Connection::connect()?;
+    (titles, errors)
+}
 
-        let tree = i3_conn.get_tree()?;
-        let mut properties: Vec<HashMap<WindowProperty, String>> = Vec::new();
-        let workspaces = super::get_workspaces(tree);
-        for workspace in &workspaces {
-            let window_props = {
-                let mut f = super::get_properties(vec![workspace.floating_nodes.iter().collect()]);
-                let mut n = super::get_properties(vec![workspace.nodes.iter().collect()]);
-                n.append(&mut f);
-                n
-            };
-            for p in window_props {
-                properties.push(p);
-            }
+/// Re-resolve titles that collide within a workspace using the secondary property set by
+/// `disambiguate`, either "instance" or "name", checking that property's aliases first
+fn disambiguate(
+    titles: &mut [Title],
+    props: &[&HashMap<WindowProperty, String>],
+    config: &Config,
+    res: &regex::Compiled,
+) {
+    let (property, patterns) = match config.general.get("disambiguate").map(|s| s.as_str()) {
+        Some("instance") => (WindowProperty::Instance, &res.instance),
+        Some("name") => (WindowProperty::Title, &res.name),
+        _ => return,
+    };
+    let counts = titles.iter().map(|title| title.name.to_owned()).counts();
+    for (title, props) in titles.iter_mut().zip(props) {
+        if counts[&title.name] < 2 {
+            continue;
         }
-        let config = super::Config::default();
-        let res = super::regex::parse_config(&config)?;
-        let result: Result<Vec<String>, _> = properties
+        if let Some(value) = props.get(&property) {
+            let alias = find_alias(value, patterns, config);
+            // Replacing the template too, the colliding one would render the same for every title
+            let (name, template) = parse_template(alias.unwrap_or(value).to_owned());
+            title.aliased = alias.is_some();
+            title.name = name;
+            title.template = template;
+        }
+    }
+}
+
+/// Join formatted titles using separator, prefixed by a space. Adjacent titles with the same
+/// key can use a separate `group_separator`. A single title can be formatted using
+/// `single_title_format`, where `{title}` is replaced with the title. Each shown title can be
+/// prefixed with `title_prefix`, which combined with an empty separator replaces it.
+fn join_titles(segments: &[Segment], config: &Config) -> String {
+    let separator = config.general.get("separator").map_or(" | ", |s| s.as_str());
+    let group_separator = config.general.get("group_separator");
+    let prefix = config.general.get("title_prefix").map_or("", |s| s.as_str());
+
+    // Merge runs of adjacent segments with the same key, keeping the first
+    let merged: Vec<Segment>;
+    let segments = if get_option(&config, "merge_runs") {
+        merged = segments
             .iter()
-            .map(|props| super::get_title(&props, &config, &res))
+            .dedup_by(|a, b| a.key == b.key)
+            .cloned()
             .collect();
-        assert_eq!(result?, vec!["Gpick", "XTerm"]);
-        Ok(())
+        &merged[..]
+    } else {
+        segments
+    };
+
+    // Hidden titles are left empty rather than showing a lone prefix
+    let prefixed = |segment: &Segment| {
+        if segment.text.is_empty() {
+            String::new()
+        } else {
+            format!("{}{}", prefix, segment.text)
+        }
+    };
+
+    if let (1, Some(format)) = (segments.len(), config.general.get("single_title_format")) {
+        return format.replace("{title}", &prefixed(&segments[0]));
     }
 
-    #[test]
-    fn collect_titles() -> Result<(), Box<dyn Error>> {
-        env::set_var("DISPLAY", ":99.0");
-        let mut i3_conn = super::I3Connection::connect()?;
-        let tree = i3_conn.get_tree()?;
-        let workspaces = super::get_workspaces(tree);
-        let mut result: Vec<Vec<String>> = Vec::new();
-        let config = super::Config::default();
-        let res = super::regex::parse_config(&config)?;
-        for workspace in workspaces {
-            result.push(super::collect_titles(&workspace, &config, &res));
+    let mut titles = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        if i > 0 {
+            match group_separator {
+                Some(group_separator) if segments[i - 1].key == segment.key => {
+                    titles.push_str(group_separator)
+                }
+                _ => titles.push_str(separator),
+            }
         }
-        let expected = vec![vec!["Gpick", "XTerm"]];
-        assert_eq!(result, expected);
-        Ok(())
+        titles.push_str(&prefixed(segment));
     }
 
-    #[test]
-    fn get_properties() -> Result<(), Box<dyn Error>> {
-        env::set_var("DISPLAY", ":99.0");
+    // Icon only output can optionally be packed tightly against the workspace number, and a
+    // prefix starting with whitespace already provides the space
+    let no_leading_space = (get_option(&config, "no_names")
+        && get_option(&config, "no_leading_space"))
+        || titles.starts_with(char::is_whitespace);
+    if !titles.is_empty() && !no_leading_space {
+        format!(" {}", titles)
+    } else {
+        titles
+    }
+}
+
+/// Check if node or any of its descendants has focus
+fn is_focused(node: &Node, depth: usize) -> bool {
+    if exceeds_depth(depth) {
+        return false;
+    }
+    node.focused
+        || node
+            .nodes
+            .iter()
+            .chain(node.floating_nodes.iter())
+            .any(|n| is_focused(n, depth + 1))
+}
+
+/// Count the urgent windows in node and its descendants
+fn count_urgent(node: &Node, depth: usize) -> usize {
+    if exceeds_depth(depth) {
+        return 0;
+    }
+    let own = usize::from(node.urgent && node.window_properties.is_some());
+    own + node
+        .nodes
+        .iter()
+        .chain(node.floating_nodes.iter())
+        .map(|n| count_urgent(n, depth + 1))
+        .sum::<usize>()
+}
+
+/// Check if node or any of its descendants is urgent
+fn is_urgent(node: &Node, depth: usize) -> bool {
+    if exceeds_depth(depth) {
+        return false;
+    }
+    node.urgent
+        || node
+            .nodes
+            .iter()
+            .chain(node.floating_nodes.iter())
+            .any(|n| is_urgent(n, depth + 1))
+}
+
+/// Escape text for use in Pango markup
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Wrap titles of an urgent workspace in the `urgent_format` template, like a Pango span, with
+/// titles escaped for markup, keeping any leading space outside the template
+fn format_urgent(titles: String, format: &str) -> String {
+    let text = titles.trim_start();
+    let space = &titles[..titles.len() - text.len()];
+    format!("{}{}", space, format.replace("{titles}", &escape_markup(text)))
+}
+
+/// Split Pango markup into its text and the tags and entities between it, flagging text parts
+fn markup_parts(markup: &str) -> Vec<(bool, &str)> {
+    let mut parts = Vec::new();
+    let mut rest = markup;
+    while let Some(start) = rest.find(|c: char| c == '<' || c == '&') {
+        let end = if rest[start..].starts_with('<') {
+            '>'
+        } else {
+            ';'
+        };
+        let len = match rest[start..].find(end) {
+            Some(len) => len + 1,
+            None => break,
+        };
+        parts.push((true, &rest[..start]));
+        parts.push((false, &rest[start..start + len]));
+        rest = &rest[start + len..];
+    }
+    parts.push((true, rest));
+    parts
+}
+
+/// Length of a name in grapheme clusters as shown, for markup counting only the text, with
+/// entities as one
+fn visible_len(name: &str, markup: bool) -> usize {
+    if !markup {
+        return name.graphemes(true).count();
+    }
+    markup_parts(name)
+        .into_iter()
+        .map(|(text, part)| match (text, part.starts_with('&')) {
+            (true, _) => part.graphemes(true).count(),
+            (false, true) => 1,
+            (false, false) => 0,
+        })
+        .sum()
+}
+
+/// Get the character used to split workspace name into initial and titles
+fn get_split_at(config: &Config) -> char {
+    match config.general.get("split_at") {
+        Some(s) => {
+            if !s.is_empty() {
+                s.chars().next().unwrap()
+            } else {
+                ' '
+            }
+        }
+        None => ' ',
+    }
+}
+
+/// Get the leading workspace number of a name, if any
+fn get_number(name: &str) -> Option<&str> {
+    let end = name
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(name.len());
+    if end > 0 {
+        Some(&name[..end])
+    } else {
+        None
+    }
+}
+
+/// Get the initial element of a workspace name we want to keep
+fn get_initial<'a>(name: &'a str, config: &Config, res: &regex::Compiled) -> &'a str {
+    // Extract exactly the number when configured, instead of splitting. Names it doesn't match,
+    // like named workspaces, are split as usual so they keep their name
+    if let Some(m) = res.number.as_ref().and_then(|re| re.find(name)) {
+        return m.as_str();
+    }
+    let split_at = get_split_at(config);
+    let mut segments = name.splitn(3, split_at);
+    let first = segments.next().unwrap_or("");
+
+    // Keep a middle segment like the output hint in "1:DP1: titles", titles always follow the
+    // split with a space, so a segment starting with one is never a middle segment. That only
+    // tells them apart when splitting on something other than whitespace
+    if get_option(config, "keep_middle_segment") && !split_at.is_whitespace() {
+        let middle = segments
+            .next()
+            .filter(|m| !m.is_empty() && !m.starts_with(char::is_whitespace));
+        if let Some(middle) = middle {
+            return &name[..first.len() + split_at.len_utf8() + middle.len()];
+        }
+    }
+    first
+}
+
+/// Get the width and character to pad workspace numbers with, set by `pad_number` and
+/// `pad_char`, defaulting to zero padding
+fn get_padding(config: &Config) -> Option<(usize, char)> {
+    let width = config.general.get("pad_number")?.parse().ok()?;
+    let pad = config
+        .general
+        .get("pad_char")
+        .and_then(|c| c.chars().next())
+        .unwrap_or('0');
+    Some((width, pad))
+}
+
+/// Strip any padding from the initial part of a name, so lookups by number ignore it
+fn unpad<'a>(initial: &'a str, config: &Config) -> &'a str {
+    match get_padding(config) {
+        Some((_, pad)) => initial.trim_start_matches(pad),
+        None => initial,
+    }
+}
+
+/// Pad the numeric prefix of the initial part of a name to a width, names without a numeric
+/// prefix are left alone
+fn pad_number(initial: &str, config: &Config) -> String {
+    let unpadded = unpad(initial, config);
+    match (get_padding(config), get_number(unpadded)) {
+        (Some((width, pad)), Some(number)) => {
+            let padding = pad.to_string().repeat(width.saturating_sub(number.len()));
+            format!("{}{}", padding, unpadded)
+        }
+        _ => initial.to_string(),
+    }
+}
+
+/// Workspace details used when formatting its name
+struct Context<'a> {
+    focused: bool,
+    count: usize,
+    output: &'a str,
+    active_output: bool,
+    previous: bool,
+    markup: bool,
+}
+
+/// Replace `{token}` placeholders in format in a single pass, so values containing something
+/// like a token, such as window titles, are left as is. Unknown tokens are kept
+fn render_format(format: &str, tokens: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(format.len());
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let token = rest[start + 1..]
+            .find('}')
+            .map(|end| &rest[start + 1..start + 1 + end]);
+        match token.and_then(|token| tokens.iter().find(|(name, _)| *name == token)) {
+            Some((name, value)) => {
+                out.push_str(value);
+                rest = &rest[start + name.len() + 2..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Format a new workspace name from the old name, keeping the initial part, and the titles
+fn format_workspace_name(
+    old: &str,
+    titles: &str,
+    ctx: &Context,
+    config: &Config,
+    res: &regex::Compiled,
+) -> String {
+    let split_at = get_split_at(config);
+    let initial = get_initial(old, config, res);
+    let number = get_number(unpad(initial, config));
+
+    // Static workspace icons are shown regardless of content
+    let content = match number.and_then(|n| config.workspace_icons.get(n)) {
+        Some(icon) => format!(" {}{}", icon, titles),
+        None => titles.to_string(),
+    };
+
+    // Per workspace number labels, falling back to general empty label
+    // with a separate hint for the focused empty workspace
+    let show_empty_label = ctx.focused || !get_option(&config, "empty_label_focused_only");
+    let focused_empty_label = config
+        .general
+        .get("focused_empty_label")
+        .filter(|_| ctx.focused);
+    let empty_label = if titles.is_empty() && show_empty_label {
+        focused_empty_label.or_else(|| {
+            number
+                .and_then(|n| config.empty_labels.get(n))
+                .or_else(|| config.general.get("empty_label"))
+        })
+    } else {
+        None
+    };
+
+    // Marker for workspaces on the focused output
+    let active = match config.general.get("active_output_marker") {
+        Some(marker) if ctx.active_output => marker.as_str(),
+        _ => "",
+    };
+
+    // Marker for the workspace `workspace back_and_forth` would switch to
+    let previous = match config.general.get("previous_marker") {
+        Some(marker) if ctx.previous => marker.as_str(),
+        _ => "",
+    };
+
+    if let Some(format) = config.general.get("format") {
+        let (num, split, count) = (
+            pad_number(initial, config),
+            split_at.to_string(),
+            ctx.count.to_string(),
+        );
+        let tokens = [
+            ("num", num.as_str()),
+            ("split", split.as_str()),
+            ("titles", content.strip_prefix(' ').unwrap_or(&content)),
+            ("count", count.as_str()),
+            ("output", ctx.output),
+            ("empty", empty_label.map_or("", |s| s.as_str())),
+            ("active", active),
+            ("previous", previous),
+        ];
+        let new = render_format(format, &tokens);
+        return apply_final_case(new, ctx, config);
+    }
+
+    let mut new: String = pad_number(initial, config);
+
+    // if we do split on colon we need to insert a new one, cause it gets split out
+    if split_at == ':' && !initial.is_empty() && !content.is_empty() {
+        new.push(':');
+    }
+    // Push new window titles to new string
+    if !content.is_empty() {
+        new.push_str(&content);
+    }
+
+    if let Some(empty_label) = empty_label {
+        new.push_str(" ");
+        new.push_str(empty_label);
+    }
+
+    apply_final_case(new, ctx, config)
+}
+
+/// Get the maximum length of a whole workspace name in grapheme clusters, set by
+/// `max_label_length`
+fn get_max_label_length(config: &Config) -> Option<usize> {
+    config.general.get("max_label_length")?.parse().ok()
+}
+
+/// Render titles and format the workspace name, dropping trailing titles while the name is longer
+/// than `max_label_length`, keeping at least one, then truncating what's left without cutting the
+/// initial part when the name starts with it. Markup is never truncated, since that could cut a
+/// tag or leave one unclosed. Returns the rendered titles and the name
+fn fit_label(
+    segments: &[Segment],
+    old: &str,
+    ctx: &Context,
+    config: &Config,
+    res: &regex::Compiled,
+    render: impl Fn(&[Segment]) -> String,
+) -> (String, String) {
+    let max = get_max_label_length(config);
+    let mut shown = segments.len();
+    loop {
+        let titles = render(&segments[..shown]);
+        let new = format_workspace_name(old, &titles, ctx, config, res);
+        let max = match max {
+            Some(max) => max,
+            None => return (titles, new),
+        };
+        if shown > 1 && visible_len(&new, ctx.markup) > max {
+            shown -= 1;
+            continue;
+        }
+        if ctx.markup {
+            return (titles, new);
+        }
+        let head = apply_case(pad_number(get_initial(old, config, res), config), config);
+        let keep = if new.starts_with(&head) {
+            head.graphemes(true).count()
+        } else {
+            0
+        };
+        return (titles, truncate_label(new, keep, max));
+    }
+}
+
+/// Truncate a whole name to max grapheme clusters with an ellipsis, never cutting the first keep
+/// grapheme clusters, which hold the part of the name kept before the titles
+fn truncate_label(name: String, keep: usize, max: usize) -> String {
+    let graphemes: Vec<&str> = name.graphemes(true).collect();
+    if graphemes.len() <= max {
+        return name;
+    }
+    let keep = keep.min(graphemes.len());
+    let head = graphemes[..keep].concat();
+    match max.saturating_sub(keep) {
+        0 => head,
+        budget => head + &truncate_title(&graphemes[keep..].concat(), budget, "head"),
+    }
+}
+
+/// Force the case of the final workspace name if `final_case` is set to "lower" or "upper",
+/// glyphs and digits have no case and are left alone
+fn apply_case(name: String, config: &Config) -> String {
+    match config.general.get("final_case").map(|s| s.as_str()) {
+        Some("lower") => name.to_lowercase(),
+        Some("upper") => name.to_uppercase(),
+        _ => name,
+    }
+}
+
+/// Force the case of the final workspace name, only of its text when it holds markup, since Pango
+/// tags and entities are case sensitive
+fn apply_final_case(name: String, ctx: &Context, config: &Config) -> String {
+    if !ctx.markup {
+        return apply_case(name, config);
+    }
+    markup_parts(&name)
+        .into_iter()
+        .map(|(text, part)| match text {
+            true => apply_case(part.to_string(), config),
+            false => part.to_string(),
+        })
+        .collect()
+}
+
+/// Format a word level diff of old and new workspace name, with removed words in red and added
+/// words in green, or plain if color is disabled
+fn format_diff(old: &str, new: &str, color: bool) -> String {
+    if !color {
+        return format!("\"{}\" -> \"{}\"", old, new);
+    }
+    let old_words: Vec<&str> = old.split(' ').collect();
+    let new_words: Vec<&str> = new.split(' ').collect();
+    let (n, m) = (old_words.len(), new_words.len());
+
+    // Longest common subsequence lengths of the word suffixes
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old_words[i] == new_words[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut old_out = Vec::new();
+    let mut new_out = Vec::new();
+    while i < n || j < m {
+        if i < n && j < m && old_words[i] == new_words[j] {
+            old_out.push(old_words[i].to_string());
+            new_out.push(new_words[j].to_string());
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            new_out.push(format!("\x1b[32m{}\x1b[0m", new_words[j]));
+            j += 1;
+        } else {
+            old_out.push(format!("\x1b[31m{}\x1b[0m", old_words[i]));
+            i += 1;
+        }
+    }
+    format!("\"{}\" -> \"{}\"", old_out.join(" "), new_out.join(" "))
+}
+
+/// Pipe a name through an external filter command, falling back to the name unchanged if the
+/// filter fails, returns nothing, or doesn't finish within timeout
+fn filter_name(command: &str, name: String, timeout: Duration) -> String {
+    match run_filter(command, &name, timeout) {
+        Ok(filtered) => filtered,
+        Err(e) => {
+            if VERBOSE.load(Ordering::Relaxed) {
+                eprintln!("name_filter_command error: \"{}\" for name \"{}\"", e, name);
+            }
+            name
+        }
+    }
+}
+
+/// Run filter with name on stdin, reading the filtered name from stdout on a separate thread so
+/// a stalled filter can be killed on timeout
+fn run_filter(command: &str, name: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let mut args = command.split_whitespace();
+    let program = args.next().ok_or("empty command")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // Dropping stdin after writing closes it, so the filter sees end of input
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(name.as_bytes())?;
+    }
+    let mut stdout = child.stdout.take().ok_or("failed to capture stdout")?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        let result = stdout.read_to_string(&mut output).map(|_| output);
+        sender.send(result).ok();
+    });
+    let output = match receiver.recv_timeout(timeout) {
+        Ok(output) => output?,
+        Err(_) => {
+            child.kill()?;
+            child.wait()?;
+            Err(format!("timed out after {}ms", timeout.as_millis()))?
+        }
+    };
+    let status = child.wait()?;
+    if !status.success() {
+        Err(format!("filter {}", status))?;
+    }
+    match output.trim_end_matches('\n') {
+        "" => Err("filter returned an empty name")?,
+        filtered => Ok(filtered.to_string()),
+    }
+}
+
+/// Rewrite the numeric prefix of a name to match i3's workspace number, correcting drift. Names
+/// without a numeric prefix, or workspaces without a number, are left alone
+fn sync_number(name: &str, num: i32, config: &Config) -> String {
+    match get_number(name) {
+        // Padding is applied again after the number is replaced
+        Some(prefix) if num >= 0 => {
+            pad_number(&format!("{}{}", num, &name[prefix.len()..]), config)
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Compare names modulo whitespace, so renames that would only change spacing are skipped,
+/// avoiding needless renames and flicker
+fn same_name(old: &str, new: &str) -> bool {
+    old.split_whitespace().eq(new.split_whitespace())
+}
+
+/// Cap the number of workspaces processed to `max_workspaces`, keeping the lowest numbered, as a
+/// safety valve against scripts spawning huge amounts of workspaces. Warns once each time the
+/// limit is exceeded, rather than on every update
+fn limit_workspaces(
+    mut workspaces: Vec<Node>,
+    config: &Config,
+    state: &mut State,
+) -> Result<Vec<Node>, Box<dyn Error>> {
+    let max: usize = match config.general.get("max_workspaces") {
+        Some(max) => max.parse()?,
+        None => return Ok(workspaces),
+    };
+    state.limit_warned &= workspaces.len() > max;
+    if workspaces.len() > max {
+        if !state.limit_warned {
+            eprintln!(
+                "Warning: {} workspaces exceeds max_workspaces, only renaming the first {}",
+                workspaces.len(),
+                max
+            );
+            state.limit_warned = true;
+        }
+        workspaces.sort_by_key(|workspace| {
+            workspace
+                .name
+                .as_deref()
+                .and_then(get_number)
+                .and_then(|n| n.parse::<u64>().ok())
+                .unwrap_or(u64::MAX)
+        });
+        workspaces.truncate(max);
+    }
+    Ok(workspaces)
+}
+
+/// Check if workspaces on output should be left alone, per `exclude_outputs`
+fn is_excluded(output: &str, res: &regex::Compiled) -> bool {
+    res.exclude_outputs.iter().any(|re| re.is_match(output))
+}
+
+/// Format the rename command, by name since renaming by number is Sway only
+fn rename_command(old: &str, new: &str) -> String {
+    format!("rename workspace {} to {}", quote_name(old), quote_name(new))
+}
+
+/// Run a command, also treating outcomes i3 rejected as errors, like renaming to a name that's
+/// already taken, which still gets a reply
+fn run_command(i3_conn: &mut I3Connection, command: &str) -> Result<(), Box<dyn Error>> {
+    let reply = i3_conn.run_command(command)?;
+    match reply.outcomes.into_iter().find(|outcome| !outcome.success) {
+        Some(outcome) => Err(outcome.error.unwrap_or_else(|| "command failed".to_string()))?,
+        None => Ok(()),
+    }
+}
+
+/// Quote a workspace name for an i3 command, escaping backslashes and quotes
+fn quote_name(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Send a desktop notification about an error with `notify-send` when `notify_on_error` is set,
+/// at most once per `notify_interval_ms`, defaulting to a minute
+pub fn notify_error(message: &str, config: &Config, state: &mut State) {
+    if !get_option(&config, "notify_on_error") {
+        return;
+    }
+    let interval = match get_duration(config, "notify_interval_ms") {
+        Ok(interval) => interval.unwrap_or(Duration::from_secs(60)),
+        Err(_) => Duration::from_secs(60),
+    };
+    if state.last_notify.map_or(false, |last| last.elapsed() < interval) {
+        return;
+    }
+    state.last_notify = Some(Instant::now());
+    let message = message.to_string();
+    thread::spawn(move || {
+        let error = match Command::new("notify-send").args(["i3wsr", &message]).status() {
+            Ok(status) if status.success() => return,
+            Ok(status) => status.to_string(),
+            Err(e) => e.to_string(),
+        };
+        if VERBOSE.load(Ordering::Relaxed) {
+            eprintln!("notify-send error: \"{}\"", error);
+        }
+    });
+}
+
+/// Run a command after a rename on a separate thread, so the event loop isn't blocked. The
+/// command is split on whitespace and not run through a shell, so `{old}`, `{new}` and `{num}` are
+/// substituted per argument and window titles can't inject shell syntax
+fn run_hook(hook: &str, old: &str, new: &str, num: Option<&str>) {
+    let mut args = hook.split_whitespace().map(|arg| {
+        arg.replace("{old}", old)
+            .replace("{new}", new)
+            .replace("{num}", num.unwrap_or(""))
+    });
+    let program = match args.next() {
+        Some(program) => program,
+        None => return,
+    };
+    let args: Vec<String> = args.collect();
+    thread::spawn(move || {
+        let error = match Command::new(&program).args(&args).status() {
+            Ok(status) if status.success() => return,
+            Ok(status) => status.to_string(),
+            Err(e) => e.to_string(),
+        };
+        if VERBOSE.load(Ordering::Relaxed) {
+            eprintln!("post_rename_command error: \"{}\" running {}", error, program);
+        }
+    });
+}
+
+/// Tree wide details needed to compute workspace names
+struct Snapshot {
+    outputs: HashMap<i64, String>,
+    focused_output: Option<String>,
+    numbers: HashMap<String, i32>,
+    filter_timeout: Duration,
+    min_interval: Option<Duration>,
+    empty_linger: Option<Duration>,
+    loop_cooldown: Option<Duration>,
+    loop_window: Duration,
+    loop_limit: usize,
+}
+
+impl Snapshot {
+    fn new(
+        i3_conn: &mut I3Connection,
+        tree: &Node,
+        config: &Config,
+    ) -> Result<Self, Box<dyn Error>> {
+        let numbers = if get_option(&config, "sync_number") {
+            i3_conn
+                .get_workspaces()?
+                .workspaces
+                .into_iter()
+                .map(|workspace| (workspace.name, workspace.num))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Snapshot {
+            outputs: get_workspace_outputs(tree),
+            focused_output: get_focused_output(tree),
+            numbers,
+            filter_timeout: get_duration(config, "name_filter_timeout_ms")?
+                .unwrap_or(Duration::from_millis(200)),
+            min_interval: get_duration(config, "min_rename_interval_ms")?,
+            empty_linger: get_duration(config, "empty_linger_ms")?,
+            loop_cooldown: get_duration(config, "loop_cooldown_ms")?,
+            loop_window: get_duration(config, "loop_window_ms")?.unwrap_or(Duration::from_secs(1)),
+            loop_limit: match config.general.get("loop_limit") {
+                Some(limit) => limit.parse()?,
+                None => 4,
+            },
+        })
+    }
+
+    fn output(&self, workspace: &Node) -> &str {
+        self.outputs.get(&workspace.id).map_or("", |s| s.as_str())
+    }
+}
+
+/// A workspace's current name, and the name computed for it
+pub struct Proposal {
+    pub old: String,
+    pub new: String,
+    /// No titles are shown for the workspace
+    pub empty: bool,
+}
+
+impl Proposal {
+    /// Check if the workspace would be renamed
+    pub fn changed(&self) -> bool {
+        !same_name(&self.old, &self.new)
+    }
+}
+
+/// Compute the new name for a workspace, without renaming it
+fn compute_workspace_name(
+    workspace: &Node,
+    focused: bool,
+    snapshot: &Snapshot,
+    config: &Config,
+    res: &regex::Compiled,
+    state: &mut State,
+) -> Result<Proposal, Box<dyn Error>> {
+    let (titles, errors) = collect_titles(workspace, config, res);
+    state.windows += titles.len() + errors.len();
+    state.title_failures += errors.len();
+    // Missing titles are skipped silently unless verbose, or `on_missing_title = "error"`
+    let on_missing_title = config.general.get("on_missing_title").map(|s| s.as_str());
+    if let Some(e) = errors.first() {
+        let message = format!("get_title error: \"{}\" for workspace {:?}", e, workspace.name);
+        notify_error(&message, config, state);
+    }
+    if VERBOSE.load(Ordering::Relaxed) || on_missing_title == Some("error") {
+        for e in errors {
+            eprintln!("get_title error: \"{}\" for workspace {:?}", e, workspace.name);
+        }
+    }
+    let count = titles.len();
+    let output = snapshot.output(workspace);
+    let titles = if get_option(&config, "category_icons_only") {
+        format_categories(&titles, output, config, res)
+    } else {
+        format_titles(&titles, output, config, res)
+    };
+    let render = |segments: &[Segment]| {
+        let titles = join_titles(segments, config);
+        let titles = match config.general.get("urgent_format") {
+            Some(format) if !titles.is_empty() && is_urgent(workspace, 0) => {
+                format_urgent(titles, format)
+            }
+            _ => titles,
+        };
+        // Show how many windows are urgent, alongside or instead of the urgent format
+        match config.general.get("urgent_count_format") {
+            Some(format) if !titles.is_empty() => match count_urgent(workspace, 0) {
+                0 => titles,
+                urgent => titles + &format.replace("{count}", &urgent.to_string()),
+            },
+            _ => titles,
+        }
+    };
+    let old: String = workspace.name.to_owned().ok_or_else(|| {
+        format!(
+            "Failed to get workspace name for workspace: {:#?}",
+            workspace
+        )
+    })?;
+
+    let ctx = Context {
+        focused,
+        count,
+        output,
+        active_output: snapshot.focused_output.as_deref() == Some(output),
+        previous: state.previous == Some(workspace.id),
+        markup: config.general.contains_key("urgent_format") && is_urgent(workspace, 0),
+    };
+    let (titles, new) = fit_label(&titles, &old, &ctx, config, res, render);
+    let new = match snapshot.numbers.get(&old) {
+        Some(num) => sync_number(&new, *num, config),
+        None => new,
+    };
+    let new = match config.general.get("name_filter_command") {
+        Some(command) => filter_name(command, new, snapshot.filter_timeout),
+        None => new,
+    };
+    Ok(Proposal {
+        old,
+        new,
+        empty: titles.is_empty(),
+    })
+}
+
+/// Compute proposed names for all workspaces, without renaming any, for a dry run
+pub fn plan(
+    i3_conn: &mut I3Connection,
+    config: &Config,
+    res: &regex::Compiled,
+) -> Result<Vec<Proposal>, Box<dyn Error>> {
+    let tree = i3_conn.get_tree()?;
+    let snapshot = Snapshot::new(i3_conn, &tree, config)?;
+    let mut state = State::default();
+    let mut proposals = Vec::new();
+    for workspace in limit_workspaces(get_workspaces(tree), config, &mut state)? {
+        if is_excluded(snapshot.output(&workspace), res) {
+            continue;
+        }
+        let focused = is_focused(&workspace, 0);
+        proposals.push(compute_workspace_name(
+            &workspace, focused, &snapshot, config, res, &mut state,
+        )?);
+    }
+    Ok(proposals)
+}
+
+/// Format proposals as a table of current name, proposed name, and whether it would change
+pub fn format_report(proposals: &[Proposal]) -> String {
+    let width = |name: &String| name.chars().count();
+    let old_width = proposals.iter().map(|p| width(&p.old)).max().unwrap_or(0).max(7);
+    let new_width = proposals.iter().map(|p| width(&p.new)).max().unwrap_or(0).max(8);
+    let mut report = format!(
+        "{:old_width$}  {:new_width$}  changed",
+        "current", "proposed"
+    );
+    for proposal in proposals {
+        let changed = if proposal.changed() { "yes" } else { "no" };
+        report.push_str(&format!(
+            "\n{:old_width$}  {:new_width$}  {}",
+            proposal.old, proposal.new, changed
+        ));
+    }
+    report
+}
+
+/// Update a single workspace name, pushing rename failures so the other workspaces can still
+/// be renamed
+fn update_workspace(
+    i3_conn: &mut I3Connection,
+    workspace: &Node,
+    snapshot: &Snapshot,
+    config: &Config,
+    res: &regex::Compiled,
+    state: &mut State,
+    failures: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    if is_excluded(snapshot.output(workspace), res) {
+        return Ok(());
+    }
+    // Leave the focused workspace alone until it loses focus, avoiding focus switch bugs
+    let focused = is_focused(workspace, 0);
+    if focused && get_option(&config, "skip_focused") {
+        if let Some(name) = &workspace.name {
+            state.names.insert(workspace.id, name.to_owned());
+        }
+        return Ok(());
+    }
+    let Proposal { old, new, empty } =
+        compute_workspace_name(workspace, focused, snapshot, config, res, state)?;
+
+    // A window reappearing cancels a pending revert
+    if !empty {
+        state.emptied.remove(&workspace.id);
+    }
+
+    // Dispatch to i3
+    if same_name(&old, &new) {
+        state.names.insert(workspace.id, old);
+        return Ok(());
+    }
+    // Let the previous name linger on a workspace that just emptied, reverting once the
+    // linger has passed
+    if let (true, Some(linger)) = (empty, snapshot.empty_linger) {
+        let emptied = *state.emptied.entry(workspace.id).or_insert_with(Instant::now);
+        if emptied.elapsed() < linger {
+            state.defer(emptied + linger);
+            state.names.insert(workspace.id, old);
+            return Ok(());
+        }
+    }
+    // Throttle renames per workspace, deferring the latest state until interval has passed
+    let last_rename = state.last_rename.get(&workspace.id).copied();
+    if let (Some(interval), Some(last)) = (snapshot.min_interval, last_rename) {
+        if last.elapsed() < interval {
+            state.defer(last + interval);
+            state.names.insert(workspace.id, old);
+            return Ok(());
+        }
+    }
+    // Back off from a workspace caught in a rename loop until its cooldown has passed
+    if let Some(until) = state.cooldown.get(&workspace.id).copied() {
+        if Instant::now() < until {
+            state.defer(until);
+            state.names.insert(workspace.id, old);
+            return Ok(());
+        }
+    }
+    if VERBOSE.load(Ordering::Relaxed) {
+        let color = io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+        println!("{}", format_diff(&old, &new, color));
+    }
+    let command = rename_command(&old, &new);
+    if let Err(e) = run_command(i3_conn, &command) {
+        if VERBOSE.load(Ordering::Relaxed) {
+            eprintln!("rename error: \"{}\" for workspace \"{}\"", e, old);
+        }
+        failures.push(format!("\"{}\": {}", old, e));
+        state.names.insert(workspace.id, old);
+        return Ok(());
+    }
+    if let Some(hook) = config.general.get("post_rename_command") {
+        run_hook(hook, &old, &new, get_number(get_initial(&old, config, res)));
+    }
+    if let Some(cooldown) = snapshot.loop_cooldown {
+        if state.record_rename(workspace.id, &new, snapshot.loop_window, snapshot.loop_limit) {
+            eprintln!(
+                "rename loop detected for workspace \"{}\", pausing renames for {}ms",
+                new,
+                cooldown.as_millis()
+            );
+            state.cooldown.insert(workspace.id, Instant::now() + cooldown);
+            state.recent_renames.remove(&workspace.id);
+        }
+    }
+    state.last_rename.insert(workspace.id, Instant::now());
+    state.emptied.remove(&workspace.id);
+    state.renames += 1;
+    state.names.insert(workspace.id, new);
+    Ok(())
+}
+
+/// Combine rename failures into a single error
+fn check_failures(failures: Vec<String>) -> Result<(), Box<dyn Error>> {
+    if !failures.is_empty() {
+        Err(format!(
+            "failed to rename {} workspace(s): {}",
+            failures.len(),
+            failures.join(", ")
+        ))?;
+    }
+    Ok(())
+}
+
+/// Update all workspace names in tree
+pub fn update_tree(
+    i3_conn: &mut I3Connection,
+    config: &Config,
+    res: &regex::Compiled,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let tree = i3_conn.get_tree()?;
+    let snapshot = Snapshot::new(i3_conn, &tree, config)?;
+    state.names.clear();
+    let mut failures = Vec::new();
+    for workspace in limit_workspaces(get_workspaces(tree), config, state)? {
+        update_workspace(i3_conn, &workspace, &snapshot, config, res, state, &mut failures)?;
+    }
+    check_failures(failures)
+}
+
+/// Update only the workspace containing the container with given id, falling back to updating
+/// the whole tree when that workspace can't be found
+pub fn update_single_workspace(
+    i3_conn: &mut I3Connection,
+    id: i64,
+    config: &Config,
+    res: &regex::Compiled,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let tree = i3_conn.get_tree()?;
+    let snapshot = Snapshot::new(i3_conn, &tree, config)?;
+    let name = find_workspace_name(&tree, id, None, 0);
+    let workspace = limit_workspaces(get_workspaces(tree), config, state)?
+        .into_iter()
+        .find(|workspace| name.is_some() && workspace.name == name);
+    let workspace = match workspace {
+        Some(workspace) => workspace,
+        None => return update_tree(i3_conn, config, res, state),
+    };
+    let mut failures = Vec::new();
+    update_workspace(i3_conn, &workspace, &snapshot, config, res, state, &mut failures)?;
+    check_failures(failures)
+}
+
+/// Reset all workspace names to their initial part, stripping any titles
+pub fn reset_tree(
+    i3_conn: &mut I3Connection,
+    config: &Config,
+    res: &regex::Compiled,
+) -> Result<(), Box<dyn Error>> {
+    let tree = i3_conn.get_tree()?;
+    let outputs = get_workspace_outputs(&tree);
+    for workspace in get_workspaces(tree) {
+        let output = outputs.get(&workspace.id).map_or("", |s| s.as_str());
+        if is_excluded(output, res) {
+            continue;
+        }
+        if let Some(old) = &workspace.name {
+            let new = get_initial(old, config, res);
+            if !same_name(old, new) {
+                let command = rename_command(old, new);
+                run_command(i3_conn, &command)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check if an event should trigger renames, configured by the list of events in
+/// `trigger_events`, defaulting to all of them
+fn is_trigger(config: &Config, event: &str) -> bool {
+    match &config.trigger_events {
+        Some(events) => events.iter().any(|e| e == event),
+        None => true,
+    }
+}
+
+/// handles new and close window events, to set the workspace name based on content
+pub fn handle_window_event(
+    e: &WindowEventInfo,
+    i3_conn: &mut I3Connection,
+    config: &Config,
+    res: &regex::Compiled,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let event = match e.change {
+        WindowChange::New => "new",
+        WindowChange::Close => "close",
+        WindowChange::Move => "move",
+        WindowChange::Title => "title",
+        _ => return Ok(()),
+    };
+    if !is_trigger(config, event) {
+        return Ok(());
+    }
+
+    // Showing scratchpad windows is transient, skip to avoid flicker
+    if get_option(&config, "skip_scratchpad") {
+        let tree = i3_conn.get_tree()?;
+        let workspace = find_workspace_name(&tree, e.container.id, None, 0);
+        if is_scratchpad_show(&e.change, e.container.id, workspace.as_deref(), state) {
+            return Ok(());
+        }
+    }
+    // Let interactive moves settle before renaming, to avoid flicker
+    if let (WindowChange::Move, Some(settle)) = (&e.change, get_duration(config, "move_settle_ms")?)
+    {
+        state.postpone(Instant::now() + settle);
+        return Ok(());
+    }
+    // A title change only affects the workspace the window is on
+    if let WindowChange::Title = e.change {
+        return update_single_workspace(i3_conn, e.container.id, config, res, state);
+    }
+    update_tree(i3_conn, config, res, state)
+}
+
+/// handles ws events,
+pub fn handle_ws_event(
+    e: &WorkspaceEventInfo,
+    i3_conn: &mut I3Connection,
+    config: &Config,
+    res: &regex::Compiled,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let event = match e.change {
+        WorkspaceChange::Empty => "empty",
+        WorkspaceChange::Focus => "focus",
+        _ => return Ok(()),
+    };
+    // Remember the workspace focus moved away from, even if focus events don't trigger renames
+    if let WorkspaceChange::Focus = e.change {
+        state.previous = e.old.as_ref().map(|old| old.id);
+    }
+    if !is_trigger(config, event) {
+        return Ok(());
+    }
+    update_tree(i3_conn, config, res, state)
+}
+
+/// handles output events, so workspaces on newly connected outputs are named promptly
+pub fn handle_output_event(
+    i3_conn: &mut I3Connection,
+    config: &Config,
+    res: &regex::Compiled,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    if !is_trigger(config, "output") {
+        return Ok(());
+    }
+    update_tree(i3_conn, config, res, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use i3ipc::reply::{NodeType, WindowProperty};
+    use std::collections::{HashMap, HashSet};
+    use std::env;
+    use std::error::Error;
+
+    #[test]
+    fn connection_tree() -> Result<(), Box<dyn Error>> {
+        env::set_var("DISPLAY", ":99.0");
+        let mut i3_conn = super::I3Connection::connect()?;
+        let config = super::Config::default();
+        let res = super::regex::parse_config(&config)?;
+        let mut state = super::State::default();
+        assert!(super::update_tree(&mut i3_conn, &config, &res, &mut state).is_ok());
+        let tree = i3_conn.get_tree()?;
+        let mut name: String = String::new();
+        for output in &tree.nodes {
+            for container in &output.nodes {
+                for workspace in &container.nodes {
+                    if let NodeType::Workspace = workspace.nodetype {
+                        let ws_n = workspace.name.to_owned();
+                        name = ws_n.unwrap();
+                    }
+                }
+            }
+        }
+        assert_eq!(name, String::from("1 Gpick | XTerm"));
+        Ok(())
+    }
+
+    #[test]
+    fn get_title() -> Result<(), Box<dyn Error>> {
+        env::set_var("DISPLAY", ":99.0");
+        let mut i3_conn = super::I3Connection::connect()?;
+
+        let tree = i3_conn.get_tree()?;
+        let mut properties: Vec<HashMap<WindowProperty, String>> = Vec::new();
+        let workspaces = super::get_workspaces(tree);
+        for workspace in &workspaces {
+            let windows = super::get_workspace_windows(&workspace, None);
+            for w in windows {
+                properties.push(w.window_properties.to_owned().unwrap_or_default());
+            }
+        }
+        let config = super::Config::default();
+        let res = super::regex::parse_config(&config)?;
+        let result: Result<Vec<String>, _> = properties
+            .iter()
+            .map(|props| super::get_title(&props, &config, &res).map(|title| title.name))
+            .collect();
+        assert_eq!(result?, vec!["Gpick", "XTerm"]);
+        Ok(())
+    }
+
+    #[test]
+    fn collect_titles() -> Result<(), Box<dyn Error>> {
+        env::set_var("DISPLAY", ":99.0");
+        let mut i3_conn = super::I3Connection::connect()?;
+        let tree = i3_conn.get_tree()?;
+        let workspaces = super::get_workspaces(tree);
+        let mut result: Vec<Vec<String>> = Vec::new();
+        let config = super::Config::default();
+        let res = super::regex::parse_config(&config)?;
+        for workspace in workspaces {
+            let (titles, _) = super::collect_titles(&workspace, &config, &res);
+            result.push(titles.into_iter().map(|title| title.name).collect());
+        }
+        let expected = vec![vec!["Gpick", "XTerm"]];
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    /// Build segments keyed by their text
+    fn segments(texts: &[&str]) -> Vec<super::Segment> {
+        texts
+            .iter()
+            .map(|text| super::Segment {
+                key: text.to_string(),
+                text: text.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn join_titles() {
+        let mut config = super::Config::default();
+        let titles = segments(&["Firefox", "XTerm"]);
+        assert_eq!(super::join_titles(&titles[..1], &config), " Firefox");
+        assert_eq!(super::join_titles(&titles, &config), " Firefox | XTerm");
+
+        config
+            .general
+            .insert("single_title_format".to_string(), ":{title}".to_string());
+        assert_eq!(super::join_titles(&titles[..1], &config), ":Firefox");
+        assert_eq!(super::join_titles(&titles, &config), " Firefox | XTerm");
+        assert_eq!(super::join_titles(&[], &config), "");
+    }
+
+    #[test]
+    fn join_titles_empty_separator() {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("separator".to_string(), "".to_string());
+        let titles = segments(&["Firefox", "XTerm"]);
+        assert_eq!(super::join_titles(&titles, &config), " FirefoxXTerm");
+
+        config
+            .general
+            .insert("title_prefix".to_string(), "•".to_string());
+        assert_eq!(super::join_titles(&titles, &config), " •Firefox•XTerm");
+        assert_eq!(super::join_titles(&titles[..1], &config), " •Firefox");
+        assert_eq!(super::join_titles(&segments(&["", "XTerm"]), &config), " •XTerm");
+        assert_eq!(super::join_titles(&[], &config), "");
+
+        config
+            .general
+            .insert("title_prefix".to_string(), " •".to_string());
+        assert_eq!(super::join_titles(&titles, &config), " •Firefox •XTerm");
+    }
+
+    #[test]
+    fn join_titles_group_separator() {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("group_separator".to_string(), " ".to_string());
+        let titles = segments(&["Firefox", "XTerm", "XTerm", "Firefox"]);
+        assert_eq!(
+            super::join_titles(&titles, &config),
+            " Firefox | XTerm XTerm | Firefox"
+        );
+    }
+
+    #[test]
+    fn join_titles_merge_runs() {
+        let mut config = super::Config::default();
+        config.options.insert("merge_runs".to_string(), true);
+        let join = |texts: &[&str]| super::join_titles(&segments(texts), &config);
+        assert_eq!(join(&["Term", "Term", "Firefox"]), " Term | Firefox");
+        assert_eq!(join(&["Firefox", "Term", "Term", "Emacs"]), " Firefox | Term | Emacs");
+        assert_eq!(join(&["Firefox", "Term", "Term"]), " Firefox | Term");
+        assert_eq!(join(&["Term", "Firefox", "Term"]), " Term | Firefox | Term");
+        assert_eq!(join(&["Term", "Term"]), " Term");
+    }
+
+    #[test]
+    fn join_titles_icon_only() {
+        let mut config = super::Config::default();
+        config.options.insert("no_names".to_string(), true);
+        config.general.insert("separator".to_string(), "".to_string());
+        let titles = segments(&["🌍", "🌐"]);
+        assert_eq!(super::join_titles(&titles, &config), " 🌍🌐");
+
+        config.options.insert("no_leading_space".to_string(), true);
+        assert_eq!(super::join_titles(&titles, &config), "🌍🌐");
+    }
+
+    #[test]
+    fn count_groups() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config.options.insert("count_groups".to_string(), true);
+        let res = super::regex::parse_config(&config)?;
+        let titles: Vec<super::Title> = ["Firefox", "Term", "Firefox", "Term", "Term", "Emacs"]
+            .iter()
+            .map(|name| super::Title {
+                name: name.to_string(),
+                aliased: false,
+                key: name.to_string(),
+                focused: false,
+                template: None,
+                placeholder: false,
+            })
+            .collect();
+        let texts = |config: &super::Config| -> Vec<String> {
+            let segments = super::format_titles(&titles, "", config, &res);
+            segments.into_iter().map(|segment| segment.text).collect()
+        };
+        assert_eq!(texts(&config), ["Firefox(2)", "Term(3)", "Emacs(1)"]);
+
+        config
+            .options
+            .insert("group_count_omit_single".to_string(), true);
+        config
+            .general
+            .insert("group_count_format".to_string(), " x{count}".to_string());
+        assert_eq!(texts(&config), ["Firefox x2", "Term x3", "Emacs"]);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_duplicates() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config.options.insert("remove_duplicates".to_string(), true);
+        config.options.insert("no_names".to_string(), true);
+        config.icons.insert("Firefox".to_string(), '🌍');
+        config
+            .general
+            .insert("icon_count_suffix".to_string(), "superscript".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let title = |key: &str, focused| super::Title {
+            name: "Firefox".to_string(),
+            aliased: key != "Firefox",
+            key: key.to_string(),
+            focused,
+            template: None,
+            placeholder: false,
+        };
+        let texts = |titles: &[super::Title]| -> Vec<String> {
+            let segments = super::format_titles(titles, "", &config, &res);
+            segments.into_iter().map(|segment| segment.text).collect()
+        };
+
+        // A focused duplicate, or one aliased from another class, is still the same title
+        let titles = [title("Firefox", false), title("Firefox", true), title("firefox", false)];
+        assert_eq!(texts(&titles), ["🌍³"]);
+        assert_eq!(texts(&titles[..2]), ["🌍²"]);
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe_icons() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config.options.insert("dedupe_icons".to_string(), true);
+        config.icons.insert("Firefox".to_string(), '🌍');
+        let titles: Vec<super::Title> = ["Firefox", "Firefox"]
+            .iter()
+            .map(|name| super::Title {
+                name: name.to_string(),
+                aliased: false,
+                key: name.to_string(),
+                focused: false,
+                template: None,
+                placeholder: false,
+            })
+            .collect();
+        let texts = |config: &super::Config| -> Result<Vec<String>, Box<dyn Error>> {
+            let res = super::regex::parse_config(config)?;
+            let segments = super::format_titles(&titles, "", config, &res);
+            Ok(segments.into_iter().map(|segment| segment.text).collect())
+        };
+        assert_eq!(texts(&config)?, ["🌍 Firefox", "Firefox"]);
+
+        // Icon only titles keep their icons
+        config.options.insert("no_icon_names".to_string(), true);
+        assert_eq!(texts(&config)?, ["🌍", "🌍"]);
+        Ok(())
+    }
+
+    #[test]
+    fn output_icons() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config.icons.insert("Firefox".to_string(), 'F');
+        config.output_icons.insert(
+            "DP-1".to_string(),
+            HashMap::from([("Firefox".to_string(), 'D')]),
+        );
+        let res = super::regex::parse_config(&config)?;
+        let title = super::Title {
+            name: "Firefox".to_string(),
+            aliased: false,
+            key: "Firefox".to_string(),
+            focused: false,
+            template: None,
+            placeholder: false,
+        };
+        assert_eq!(super::get_icon(&title, "DP-1", &config, &res), Some("D".to_string()));
+        assert_eq!(super::get_icon(&title, "eDP-1", &config, &res), Some("F".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn placeholder_icon() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("default_icon".to_string(), "*".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let title = |placeholder| super::Title {
+            name: "?".to_string(),
+            aliased: false,
+            key: "?".to_string(),
+            focused: false,
+            template: None,
+            placeholder,
+        };
+        assert_eq!(super::get_icon(&title(false), "", &config, &res), Some("*".to_string()));
+        assert_eq!(super::get_icon(&title(true), "", &config, &res), None);
+        Ok(())
+    }
+
+    #[test]
+    fn format_categories() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        let categories = [("Firefox", "browser"), ("Chromium", "browser"), ("XTerm", "term")];
+        for (name, category) in categories {
+            config
+                .categories
+                .insert(name.to_string(), category.to_string());
+        }
+        config
+            .category_icons
+            .insert("browser".to_string(), "🌍".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let titles: Vec<super::Title> = ["Firefox", "XTerm", "Chromium", "Gimp"]
+            .iter()
+            .map(|name| super::Title {
+                name: name.to_string(),
+                aliased: false,
+                key: name.to_string(),
+                focused: false,
+                template: None,
+                placeholder: false,
+            })
+            .collect();
+        let segments = super::format_categories(&titles, "", &config, &res);
+        let texts: Vec<&str> = segments.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, ["🌍", "term", "Gimp"]);
+        Ok(())
+    }
+
+    #[test]
+    fn catch_all_alias() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .aliases
+            .class
+            .insert("^Firefox$".to_string(), "Fire".to_string());
+        config
+            .aliases
+            .class
+            .insert("*".to_string(), "App".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let title = |class: &str| {
+            let props = HashMap::from([(WindowProperty::Class, class.to_string())]);
+            super::get_title(&props, &config, &res).map(|title| title.name)
+        };
+        assert_eq!(title("Firefox")?, "Fire");
+        assert_eq!(title("XTerm")?, "App");
+        Ok(())
+    }
+
+    #[test]
+    fn composite_alias() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .aliases
+            .composite
+            .insert("^Firefox/Navigator$".to_string(), "Browser".to_string());
+        config
+            .aliases
+            .class
+            .insert("^Firefox$".to_string(), "Fire".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let title = |instance: &str| {
+            let props = HashMap::from([
+                (WindowProperty::Class, "Firefox".to_string()),
+                (WindowProperty::Instance, instance.to_string()),
+            ]);
+            super::get_title(&props, &config, &res).map(|title| title.name)
+        };
+        assert_eq!(title("Navigator")?, "Browser");
+        assert_eq!(title("Toolkit")?, "Fire");
+        Ok(())
+    }
+
+    #[test]
+    fn template_alias() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config.aliases.class.insert(
+            "^libreoffice-writer$".to_string(),
+            "{icon} LibreOffice Writer".to_string(),
+        );
+        config.icons.insert("LibreOffice Writer".to_string(), 'W');
+        let res = super::regex::parse_config(&config)?;
+        let props = HashMap::from([(WindowProperty::Class, "libreoffice-writer".to_string())]);
+        let title = super::get_title(&props, &config, &res)?;
+        assert_eq!(title.name, "LibreOffice Writer");
+        let icon = super::get_icon(&title, "", &config, &res);
+        assert_eq!(icon.as_deref(), Some("W"));
+        let format = |icon| super::format_with_icon(&title, icon, 1, &config);
+        assert_eq!(format(Some("W")), "W LibreOffice Writer");
+        assert_eq!(format(None), "LibreOffice Writer");
+        Ok(())
+    }
+
+    #[test]
+    fn aliased_only() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config.options.insert("aliased_only".to_string(), true);
+        config
+            .aliases
+            .class
+            .insert("^Firefox$".to_string(), "Fire".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let title = |class: &str| {
+            let props = HashMap::from([(WindowProperty::Class, class.to_string())]);
+            super::get_title(&props, &config, &res).map(|title| title.name)
+        };
+        assert_eq!(title("Firefox")?, "Fire");
+        assert_eq!(title("XTerm")?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn empty_alias() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .aliases
+            .class
+            .insert("^Gpick$".to_string(), "".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let titles: Result<Vec<super::Title>, _> = ["Gpick", "XTerm"]
+            .iter()
+            .map(|class| {
+                let props = HashMap::from([(WindowProperty::Class, class.to_string())]);
+                super::get_title(&props, &config, &res)
+            })
+            .collect();
+        let titles = super::format_titles(&titles?, "", &config, &res);
+        assert_eq!(titles, segments(&["XTerm"]));
+        Ok(())
+    }
+
+    #[test]
+    fn show_focused_name() {
+        let mut config = super::Config::default();
+        config.options.insert("show_focused_name".to_string(), true);
+        let title = |focused| super::Title {
+            name: "Firefox".to_string(),
+            aliased: false,
+            key: "Firefox".to_string(),
+            focused,
+            template: None,
+            placeholder: false,
+        };
+        let format = |config: &super::Config, focused, icon| {
+            super::format_with_icon(&title(focused), icon, 1, config)
+        };
+
+        for option in ["no_icon_names", "no_names"] {
+            config.options.insert(option.to_string(), true);
+            assert_eq!(format(&config, true, Some("🌍")), "🌍 Firefox");
+            assert_eq!(format(&config, false, Some("🌍")), "🌍");
+            assert_eq!(format(&config, true, None), "Firefox");
+            config.options.remove(option);
+        }
+        config.options.insert("no_names".to_string(), true);
+        assert_eq!(format(&config, false, None), "");
+    }
+
+    #[test]
+    fn truncate_title() {
+        let title = "Inbox — Project X";
+        assert_eq!(super::truncate_title(title, 20, "head"), title);
+        assert_eq!(super::truncate_title(title, 8, "head"), "Inbox —…");
+        assert_eq!(super::truncate_title(title, 8, "tail"), "…oject X");
+        assert_eq!(super::truncate_title(title, 8, "middle"), "… — Pro…");
+        assert_eq!(super::truncate_title("Ångström", 4, "head"), "Ång…");
+
+        // Never longer than max
+        for mode in ["head", "tail", "middle"] {
+            assert_eq!(super::truncate_title(title, 0, mode), "");
+            assert_eq!(super::truncate_title(title, 1, mode), "…");
+        }
+        assert_eq!(super::truncate_title(title, 2, "middle"), "I…");
+
+        // Composite emoji at the boundary are kept whole
+        let flag = "🇳🇴 Oslo";
+        assert_eq!(super::truncate_title(flag, 2, "head"), "🇳🇴…");
+        let family = "Family 👨‍👩‍👧‍👦 photos";
+        assert_eq!(super::truncate_title(family, 9, "head"), "Family 👨‍👩‍👧‍👦…");
+        assert_eq!(super::truncate_title(family, 9, "tail"), "…👨‍👩‍👧‍👦 photos");
+    }
+
+    #[test]
+    fn max_label_length() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        let ctx = super::Context {
+            focused: false,
+            count: 3,
+            output: "",
+            active_output: false,
+            previous: false,
+            markup: false,
+        };
+        let titles = segments(&["Firefox", "XTerm", "Emacs"]);
+        let fit = |config: &super::Config| -> Result<String, Box<dyn Error>> {
+            let res = super::regex::parse_config(config)?;
+            let render = |segments: &[super::Segment]| super::join_titles(segments, config);
+            Ok(super::fit_label(&titles, "1", &ctx, config, &res, render).1)
+        };
+        assert_eq!(fit(&config)?, "1 Firefox | XTerm | Emacs");
+
+        // Whole titles are dropped first
+        config
+            .general
+            .insert("max_label_length".to_string(), "20".to_string());
+        assert_eq!(fit(&config)?, "1 Firefox | XTerm");
+        config
+            .general
+            .insert("max_label_length".to_string(), "9".to_string());
+        assert_eq!(fit(&config)?, "1 Firefox");
+
+        // Then the last title is truncated, never cutting the number
+        config
+            .general
+            .insert("max_label_length".to_string(), "6".to_string());
+        assert_eq!(fit(&config)?, "1 Fir…");
+        config
+            .general
+            .insert("max_label_length".to_string(), "0".to_string());
+        assert_eq!(fit(&config)?, "1");
+
+        assert_eq!(super::truncate_label("10:[Q] Firefox".to_string(), 6, 4), "10:[Q]");
+        assert_eq!(super::truncate_label("1 🇳🇴🇳🇴".to_string(), 1, 3), "1 …");
+        Ok(())
+    }
+
+    #[test]
+    fn apply_case() {
+        let mut config = super::Config::default();
+        let name = || String::from("1: \u{f269} Firefox | URxvt");
+        assert_eq!(super::apply_case(name(), &config), name());
+        config.general.insert("final_case".to_string(), "lower".to_string());
+        assert_eq!(super::apply_case(name(), &config), "1: \u{f269} firefox | urxvt");
+        config.general.insert("final_case".to_string(), "upper".to_string());
+        assert_eq!(super::apply_case(name(), &config), "1: \u{f269} FIREFOX | URXVT");
+        assert_eq!(super::apply_case(String::from("1"), &config), "1");
+    }
+
+    #[test]
+    fn sync_number() {
+        let mut config = super::Config::default();
+        assert_eq!(super::sync_number("12 Firefox", 2, &config), "2 Firefox");
+        assert_eq!(super::sync_number("2:Firefox", 2, &config), "2:Firefox");
+        assert_eq!(super::sync_number("web Firefox", 2, &config), "web Firefox");
+        assert_eq!(super::sync_number("3 Firefox", -1, &config), "3 Firefox");
+
+        // Padding survives syncing the number
+        config
+            .general
+            .insert("pad_number".to_string(), "2".to_string());
+        assert_eq!(super::sync_number("01 Firefox", 1, &config), "01 Firefox");
+        assert_eq!(super::sync_number("12 Firefox", 2, &config), "02 Firefox");
+    }
+
+    #[test]
+    fn format_urgent() {
+        let format = "<span background='#900'>{titles}</span>";
+        assert_eq!(
+            super::format_urgent(" Tom & Jerry | <Term>".to_string(), format),
+            " <span background='#900'>Tom &amp; Jerry | &lt;Term&gt;</span>"
+        );
+    }
+
+    #[test]
+    fn urgent_markup() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config.general.insert(
+            "urgent_format".to_string(),
+            "<span background='#900'>{titles}</span>".to_string(),
+        );
+        config
+            .general
+            .insert("final_case".to_string(), "upper".to_string());
+        config
+            .general
+            .insert("max_label_length".to_string(), "16".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let ctx = super::Context {
+            focused: false,
+            count: 2,
+            output: "",
+            active_output: false,
+            previous: false,
+            markup: true,
+        };
+        let titles = segments(&["Tom & Jerry", "XTerm"]);
+        let render = |segments: &[super::Segment]| {
+            let titles = super::join_titles(segments, &config);
+            super::format_urgent(titles, &config.general["urgent_format"])
+        };
+
+        // Case only applies to the text, and the markup isn't counted nor truncated
+        let (_, new) = super::fit_label(&titles, "1", &ctx, &config, &res, render);
+        assert_eq!(new, "1 <span background='#900'>TOM &amp; JERRY</span>");
+        assert_eq!(super::visible_len(&new, true), 13);
+        Ok(())
+    }
+
+    #[test]
+    fn rename_command() {
+        assert_eq!(
+            super::rename_command("1", r#"1 "quoted" C:\temp"#),
+            r#"rename workspace "1" to "1 \"quoted\" C:\\temp""#
+        );
+    }
+
+    #[test]
+    fn format_report() {
+        let proposal = |old: &str, new: &str| super::Proposal {
+            old: old.to_string(),
+            new: new.to_string(),
+            empty: false,
+        };
+        let proposals = [proposal("1 Firefox", "1 Firefox"), proposal("2", "2 XTerm")];
+        assert_eq!(
+            super::format_report(&proposals),
+            "current    proposed   changed\n\
+             1 Firefox  1 Firefox  no\n\
+             2          2 XTerm    yes"
+        );
+    }
+
+    #[test]
+    fn pad_number() {
+        let mut config = super::Config::default();
+        assert_eq!(super::pad_number("1", &config), "1");
+        config
+            .general
+            .insert("pad_number".to_string(), "2".to_string());
+        assert_eq!(super::pad_number("1", &config), "01");
+        assert_eq!(super::pad_number("01", &config), "01");
+        assert_eq!(super::pad_number("10", &config), "10");
+        assert_eq!(super::pad_number("1:[Q]", &config), "01:[Q]");
+        assert_eq!(super::pad_number("web", &config), "web");
+        assert_eq!(super::unpad("01", &config), "1");
+
+        config
+            .general
+            .insert("pad_char".to_string(), "_".to_string());
+        assert_eq!(super::pad_number("1", &config), "_1");
+        assert_eq!(super::pad_number("_1", &config), "_1");
+        assert_eq!(super::pad_number("100", &config), "100");
+
+        // Whitespace padding would be ignored when comparing names, so it's rejected
+        config
+            .general
+            .insert("pad_char".to_string(), " ".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn pad_number_format() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("pad_number".to_string(), "2".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let ctx = super::Context {
+            focused: false,
+            count: 1,
+            output: "",
+            active_output: false,
+            previous: false,
+            markup: false,
+        };
+        for old in ["1", "01 Firefox", "1 XTerm"] {
+            let new = super::format_workspace_name(old, " Firefox", &ctx, &config, &res);
+            assert_eq!(new, "01 Firefox");
+            assert_eq!(super::sync_number(&new, 1, &config), "01 Firefox");
+        }
+        assert!(!super::same_name("1 XTerm", "01 Firefox"));
+        Ok(())
+    }
+
+    #[test]
+    fn number_regex() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("number_regex".to_string(), "^\\d+".to_string());
+        let res = super::regex::parse_config(&config)?;
+        assert_eq!(super::get_initial("1. Firefox", &config, &res), "1");
+        assert_eq!(super::get_initial("12foo Firefox", &config, &res), "12");
+        assert_eq!(super::get_initial("web2 Firefox", &config, &res), "web2");
+        assert_eq!(super::get_initial("web", &config, &res), "web");
+        Ok(())
+    }
+
+    #[test]
+    fn trigger_events() {
+        let mut config = super::Config::default();
+        assert!(super::is_trigger(&config, "title"));
+        config.trigger_events = Some(vec!["new".to_string(), "close".to_string()]);
+        assert!(config.validate().is_ok());
+        assert!(super::is_trigger(&config, "new"));
+        assert!(!super::is_trigger(&config, "title"));
+
+        // A misspelled event is rejected rather than disabling the intended one
+        config.trigger_events = Some(vec!["titel".to_string()]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn numeric_values() {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("move_settle_ms".to_string(), "150".to_string());
+        assert!(config.validate().is_ok());
+        for (key, value) in [("move_settle_ms", "150ms"), ("max_title_length", "-1")] {
+            let mut config = config.clone();
+            config.general.insert(key.to_string(), value.to_string());
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn exclude_outputs() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        let patterns = vec!["^HDMI-2$".to_string(), "^DP-\\d{1,2}$".to_string()];
+        config
+            .patterns
+            .insert("exclude_outputs".to_string(), patterns);
+        let res = super::regex::parse_config(&config)?;
+        assert!(super::is_excluded("HDMI-2", &res));
+        assert!(super::is_excluded("DP-12", &res));
+        assert!(!super::is_excluded("DP-123", &res));
+        assert!(!super::is_excluded("eDP-1", &res));
+
+        // The old comma separated form would split patterns like these
+        config
+            .general
+            .insert("exclude_outputs".to_string(), "^DP-\\d{1,2}$".to_string());
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_middle_segment() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("split_at".to_string(), ":".to_string());
+        let res = super::regex::parse_config(&config)?;
+        assert_eq!(super::get_initial("1:DP1: Firefox", &config, &res), "1");
+
+        config
+            .options
+            .insert("keep_middle_segment".to_string(), true);
+        assert_eq!(super::get_initial("1:DP1: Firefox", &config, &res), "1:DP1");
+        assert_eq!(super::get_initial("1:DP1", &config, &res), "1:DP1");
+        assert_eq!(super::get_initial("1: Firefox", &config, &res), "1");
+        assert_eq!(super::get_initial("1", &config, &res), "1");
+
+        // Titles can't be told apart from a middle segment when splitting on a space
+        config.general.remove("split_at");
+        assert_eq!(super::get_initial("1 Firefox | XTerm", &config, &res), "1");
+        Ok(())
+    }
+
+    #[test]
+    fn record_rename() {
+        let mut state = super::state::State::default();
+        let window = std::time::Duration::from_secs(60);
+        assert!(!state.record_rename(1, "1 Firefox", window, 2));
+        assert!(!state.record_rename(1, "1 XTerm", window, 2));
+        assert!(!state.record_rename(1, "1 Firefox|XTerm", window, 2));
+        assert!(!state.record_rename(2, "2 Firefox", window, 2));
+        assert!(state.record_rename(1, "1 Firefox", window, 2));
+    }
+
+    #[test]
+    fn unaliased_icons() {
+        let mut config = super::Config::default();
+        config.icons.insert("Firefox".to_string(), 'F');
+        config.icons.insert("Termial".to_string(), 'T');
+        assert!(config.warnings().is_empty());
+
+        config
+            .aliases
+            .class
+            .insert("^Firefox$".to_string(), "Firefox".to_string());
+        config
+            .aliases
+            .class
+            .insert("^URxvt$".to_string(), "Terminal".to_string());
+        assert_eq!(
+            config.warnings(),
+            ["icons.Termial is not the value of any alias, and only matches a window property \
+              of exactly \"Termial\""]
+        );
+    }
+
+    #[test]
+    fn is_scratchpad_show() {
+        use i3ipc::event::inner::WindowChange;
+        let mut state = super::State::default();
+        let show = |change, workspace, state: &mut super::State| {
+            super::is_scratchpad_show(&change, 1, workspace, state)
+        };
+        // Hiding renames the workspace the window left
+        assert!(!show(WindowChange::Move, Some("__i3_scratch"), &mut state));
+        // Showing it again is skipped
+        assert!(show(WindowChange::Move, Some("1"), &mut state));
+        assert!(!show(WindowChange::Title, Some("1"), &mut state));
+        // Moving it again once shown is a real move
+        assert!(!show(WindowChange::Move, Some("3"), &mut state));
+        // Moving other windows between workspaces isn't
+        assert!(!super::is_scratchpad_show(&WindowChange::Move, 2, Some("2"), &mut state));
+        // A closed window is forgotten
+        assert!(!show(WindowChange::Move, Some("__i3_scratch"), &mut state));
+        assert!(!show(WindowChange::Close, Some("1"), &mut state));
+        assert!(!show(WindowChange::Move, Some("1"), &mut state));
+    }
+
+    #[test]
+    fn same_name() {
+        assert!(super::same_name("1  Firefox", "1 Firefox"));
+        assert!(super::same_name("1 Firefox ", " 1 Firefox"));
+        assert!(!super::same_name("1 Firefox", "1:Firefox"));
+        assert!(!super::same_name("1 Firefox", "1 Firefox | URxvt"));
+    }
+
+    #[test]
+    fn empty_label_idempotent() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("empty_label".to_string(), "empty".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let ctx = super::Context {
+            focused: false,
+            count: 0,
+            output: "",
+            active_output: false,
+            previous: false,
+            markup: false,
+        };
+        // i3 may hand back the previous name with stray whitespace, which shouldn't cause a rename
+        for old in ["1 empty", "1 empty ", "1  empty"] {
+            let new = super::format_workspace_name(old, "", &ctx, &config, &res);
+            assert_eq!(new, "1 empty");
+            assert!(super::same_name(old, &new));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn focused_empty_label() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("empty_label".to_string(), "empty".to_string());
+        config
+            .general
+            .insert("focused_empty_label".to_string(), "+ open something".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let format = |titles, focused| {
+            let ctx = super::Context {
+                focused,
+                count: 0,
+                output: "",
+                active_output: false,
+                previous: false,
+                markup: false,
+            };
+            super::format_workspace_name("1", titles, &ctx, &config, &res)
+        };
+        assert_eq!(format("", true), "1 + open something");
+        assert_eq!(format("", false), "1 empty");
+        assert_eq!(format(" Firefox", true), "1 Firefox");
+        assert_eq!(format(" Firefox", false), "1 Firefox");
+        Ok(())
+    }
+
+    #[test]
+    fn render_format() {
+        let tokens = [("num", "1"), ("titles", "Use {output}"), ("output", "DP-1")];
+        assert_eq!(
+            super::render_format("{num} {titles} [{output}] {unknown}", &tokens),
+            "1 Use {output} [DP-1] {unknown}"
+        );
+        assert_eq!(super::render_format("{num}{", &tokens), "1{");
+    }
+
+    #[test]
+    fn disambiguate() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        config
+            .general
+            .insert("disambiguate".to_string(), "instance".to_string());
+        config
+            .aliases
+            .instance
+            .insert("^htop$".to_string(), "Top".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let windows = [("Alacritty", "htop"), ("Alacritty", "shell"), ("Firefox", "Navigator")];
+        let props: Vec<HashMap<WindowProperty, String>> = windows
+            .iter()
+            .map(|(class, instance)| {
+                HashMap::from([
+                    (WindowProperty::Class, class.to_string()),
+                    (WindowProperty::Instance, instance.to_string()),
+                ])
+            })
+            .collect();
+        let props: Vec<&HashMap<WindowProperty, String>> = props.iter().collect();
+        let mut titles = props
+            .iter()
+            .map(|props| super::get_title(props, &config, &res))
+            .collect::<Result<Vec<super::Title>, _>>()?;
+        super::disambiguate(&mut titles, &props, &config, &res);
+        let names: Vec<&str> = titles.iter().map(|title| title.name.as_str()).collect();
+        assert_eq!(names, ["Top", "shell", "Firefox"]);
+
+        // Colliding templates are replaced along with the name
+        config
+            .aliases
+            .class
+            .insert("^Alacritty$".to_string(), "{icon} Term".to_string());
+        let res = super::regex::parse_config(&config)?;
+        let mut titles = props
+            .iter()
+            .map(|props| super::get_title(props, &config, &res))
+            .collect::<Result<Vec<super::Title>, _>>()?;
+        assert_eq!(titles[0].template.as_deref(), Some("{icon} Term"));
+        super::disambiguate(&mut titles, &props, &config, &res);
+        let rendered: Vec<String> = titles
+            .iter()
+            .map(|title| super::format_with_icon(title, None, 1, &config))
+            .collect();
+        assert_eq!(rendered, ["Top", "shell", "Firefox"]);
+        Ok(())
+    }
+
+    #[test]
+    fn to_ascii() {
+        assert_eq!(super::to_ascii("Ångström Café"), "Angstrom Cafe");
+        assert_eq!(super::to_ascii("🌍 Firefox"), "Firefox");
+        assert_eq!(super::to_ascii("Fire 🌍 fox"), "Fire fox");
+        assert_eq!(super::to_ascii("終端"), "終端");
+    }
+
+    #[test]
+    fn format_diff() {
+        assert_eq!(
+            super::format_diff("1 Firefox", "1 Firefox | Term", false),
+            "\"1 Firefox\" -> \"1 Firefox | Term\""
+        );
+        assert_eq!(
+            super::format_diff("1 Firefox", "1 Term", true),
+            "\"1 \x1b[31mFirefox\x1b[0m\" -> \"1 \x1b[32mTerm\x1b[0m\""
+        );
+    }
+
+    #[test]
+    fn find_alias() -> Result<(), Box<dyn Error>> {
+        let mut config = super::Config::default();
+        let patterns = vec![
+            (regex::Regex::new("fire")?, String::from("Fire")),
+            (regex::Regex::new("firefox-dev")?, String::from("Firefox-dev")),
+        ];
+        let alias = super::find_alias("firefox-developer", &patterns, &config);
+        assert_eq!(alias.map(|s| s.as_str()), Some("Fire"));
+
+        config
+            .general
+            .insert("alias_match".to_string(), "longest".to_string());
+        let alias = super::find_alias("firefox-developer", &patterns, &config);
+        assert_eq!(alias.map(|s| s.as_str()), Some("Firefox-dev"));
+        assert_eq!(super::find_alias("chromium", &patterns, &config), None);
+
+        // Equally long matches resolve the same regardless of order
+        let mut patterns = vec![
+            (regex::Regex::new("fox")?, String::from("Fox")),
+            (regex::Regex::new("fir")?, String::from("Fir")),
+        ];
+        let alias = super::find_alias("firefox", &patterns, &config);
+        assert_eq!(alias.map(|s| s.as_str()), Some("Fir"));
+        patterns.reverse();
+        let alias = super::find_alias("firefox", &patterns, &config);
+        assert_eq!(alias.map(|s| s.as_str()), Some("Fir"));
+        Ok(())
+    }
+
+    #[test]
+    fn get_windows() -> Result<(), Box<dyn Error>> {
+        env::set_var("DISPLAY", ":99.0");
         let mut i3_conn = super::I3Connection::connect()?;
         let tree = i3_conn.get_tree()?;
         let workspaces = super::get_workspaces(tree);
         let mut result: Vec<HashMap<WindowProperty, String>> = Vec::new();
         for workspace in workspaces {
-            let window_props = {
-                let mut f = super::get_properties(vec![workspace.floating_nodes.iter().collect()]);
-                let mut n = super::get_properties(vec![workspace.nodes.iter().collect()]);
-                n.append(&mut f);
-                n
-            };
-            for props in window_props {
-                result.push(props)
+            let windows = super::get_workspace_windows(&workspace, None);
+            for w in windows {
+                result.push(w.window_properties.to_owned().unwrap_or_default())
             }
         }
         let result: usize = result.iter().filter(|v| !v.is_empty()).count();
         assert_eq!(result, 2);
         Ok(())
     }
+
+    #[test]
+    fn get_workspace_windows() -> Result<(), Box<dyn Error>> {
+        env::set_var("DISPLAY", ":99.0");
+        let mut i3_conn = super::I3Connection::connect()?;
+        let tree = i3_conn.get_tree()?;
+        for workspace in super::get_workspaces(tree) {
+            // XTerm is floated by the test setup, and should be collected once, after tiled windows
+            let windows = super::get_workspace_windows(&workspace, None);
+            let ids: HashSet<i64> = windows.iter().map(|w| w.id).collect();
+            assert_eq!(ids.len(), windows.len());
+            let floating_nodes = vec![workspace.floating_nodes.iter().collect()];
+            let floating = super::get_windows(floating_nodes, None);
+            assert_eq!(floating.len(), 1);
+            assert_eq!(windows.last().map(|w| w.id), floating.first().map(|w| w.id));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_depth() -> Result<(), Box<dyn Error>> {
+        env::set_var("DISPLAY", ":99.0");
+        let mut i3_conn = super::I3Connection::connect()?;
+        // A chain of containers under a workspace, ending in a focused urgent window
+        let mut chain = |depth: usize| -> Result<super::Node, Box<dyn Error>> {
+            let mut node = i3_conn.get_tree()?;
+            node.id = 1;
+            node.focused = true;
+            node.urgent = true;
+            node.window_properties = Some(HashMap::new());
+            node.nodes.clear();
+            node.floating_nodes.clear();
+            for _ in 0..depth {
+                let mut parent = i3_conn.get_tree()?;
+                parent.id = 0;
+                parent.focused = false;
+                parent.urgent = false;
+                parent.window_properties = None;
+                parent.floating_nodes.clear();
+                parent.nodes = vec![node];
+                node = parent;
+            }
+            node.nodetype = NodeType::Workspace;
+            node.name = Some("1".to_string());
+            Ok(node)
+        };
+
+        let shallow = chain(10)?;
+        assert!(super::is_focused(&shallow, 0));
+        assert_eq!(super::find_workspace_name(&shallow, 1, None, 0), Some("1".to_string()));
+        assert_eq!(super::count_urgent(&shallow, 0), 1);
+
+        // Traversal stops at the maximum depth instead of following the chain down
+        let deep = chain(super::MAX_DEPTH + 100)?;
+        assert!(!super::is_focused(&deep, 0));
+        assert!(!super::is_urgent(&deep, 0));
+        assert_eq!(super::find_workspace_name(&deep, 1, None, 0), None);
+        assert_eq!(super::count_urgent(&deep, 0), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn hidden_tabs() -> Result<(), Box<dyn Error>> {
+        env::set_var("DISPLAY", ":99.0");
+        let mut i3_conn = super::I3Connection::connect()?;
+        let mut node = |id: i64, class: Option<&str>| -> Result<super::Node, Box<dyn Error>> {
+            let mut node = i3_conn.get_tree()?;
+            node.id = id;
+            node.layout = super::NodeLayout::SplitH;
+            node.focused = false;
+            node.focus.clear();
+            node.nodes.clear();
+            node.floating_nodes.clear();
+            node.window_properties =
+                class.map(|class| HashMap::from([(WindowProperty::Class, class.to_string())]));
+            Ok(node)
+        };
+        let mut config = super::Config::default();
+        config.options.insert("collapse_tabbed".to_string(), true);
+        let res = super::regex::parse_config(&config)?;
+        let names = |workspace: &super::Node| -> Vec<String> {
+            let (titles, _) = super::collect_titles(workspace, &config, &res);
+            titles.into_iter().map(|title| title.name).collect()
+        };
+
+        for layout in [super::NodeLayout::Tabbed, super::NodeLayout::Stacked] {
+            let mut tabs = node(10, None)?;
+            tabs.layout = layout;
+            tabs.nodes = vec![
+                node(11, Some("Firefox"))?,
+                node(12, Some("XTerm"))?,
+                node(13, Some("Emacs"))?,
+            ];
+            let mut workspace = node(1, None)?;
+            workspace.nodetype = NodeType::Workspace;
+            workspace.nodes = vec![node(2, Some("Gpick"))?, tabs];
+
+            // Without focus info the first tab is shown
+            assert_eq!(names(&workspace), ["Gpick", "Firefox"]);
+
+            // Otherwise only the focused tab
+            workspace.nodes[1].focus = vec![12, 11, 13];
+            assert_eq!(names(&workspace), ["Gpick", "XTerm"]);
+        }
+        Ok(())
+    }
 }
@@ -1,9 +1,22 @@
 use clap::{Parser, ValueEnum};
 use dirs::config_dir;
-use i3ipc::{event::Event, I3Connection, I3EventListener, Subscription};
+use i3ipc::{event::Event, I3Connection, I3EventListener, MessageError, Subscription};
 use i3wsr::config::Config;
+use i3wsr::regex::Compiled;
+use i3wsr::state::State;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
 use std::error::Error;
-use std::path::Path;
+use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 enum Icons {
@@ -17,12 +30,25 @@ enum Properties {
     Name,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+enum Preset {
+    Polybar,
+    IconsOnly,
+}
+
+/// Messages handled by the main loop
+enum Message {
+    /// An event from the listener of given generation
+    Event(usize, Result<Event, MessageError>),
+    Summary,
+}
+
 /// i3wsr config
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Path to toml config file
-    #[arg(short, long)]
+    #[arg(short, long, env = "I3WSR_CONFIG")]
     config: Option<String>,
 
     /// Sets icons to be used
@@ -48,12 +74,191 @@ struct Args {
     /// What character used to split the workspace title string
     #[arg(short = 'a', long)]
     split_at: Option<String>,
+
+    /// Apply a bundle of defaults, values set in the config take precedence
+    #[arg(long)]
+    preset: Option<Preset>,
+
+    /// Overlay a [profiles.<name>] section from the config onto general settings and icons
+    #[arg(long, env = "I3WSR_PROFILE")]
+    profile: Option<String>,
+
+    /// How many times to retry connecting to i3 on startup, doubling the delay each time
+    #[arg(long, default_value_t = 5)]
+    startup_retries: u32,
+
+    /// Reset workspace names to their initial part when exiting
+    #[arg(long)]
+    reset_on_exit: bool,
+
+    /// Print a diff of old and new workspace names on rename
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Suppress informational output
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Periodically print events, renames and title failures since the last report
+    #[arg(long, value_name = "SECS")]
+    health_interval: Option<u64>,
+
+    /// Print the renames that would be issued, then exit without renaming
+    #[arg(long)]
+    dry_run: bool,
+
+    /// With --dry-run, print a table of every workspace's current and proposed name
+    #[arg(long, requires = "dry_run")]
+    report: bool,
+
+    /// Refresh names, subscribe again and check the connection to i3 when no events arrive for
+    /// this long
+    #[arg(long, value_name = "SECS")]
+    watchdog: Option<u64>,
+
+    /// Check config for errors and likely mistakes, then exit
+    #[arg(long)]
+    check_config: bool,
+
+    /// Print the config after merging files, presets, profiles and flags, then exit
+    #[arg(long)]
+    print_config: bool,
+}
+
+/// Print informational message unless quiet
+fn info(message: &str) {
+    if !i3wsr::QUIET.load(Ordering::Relaxed) {
+        println!("{}", message);
+    }
+}
+
+/// Retry connect with a doubling backoff, so that starting before i3's IPC socket is ready
+/// doesn't exit the program
+fn connect_with_retry<T, E: Display>(
+    retries: u32,
+    mut connect: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut delay = Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        match connect() {
+            Ok(conn) => return Ok(conn),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                eprintln!(
+                    "Failed to connect to i3: {}, retrying in {}ms ({}/{})",
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    retries
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Print an error from the event loop, and send a desktop notification if enabled
+fn report(context: &str, error: &dyn Display, config: &Config, state: &mut State) {
+    let message = format!("{} error: {}", context, error);
+    eprintln!("{}", message);
+    i3wsr::notify_error(&message, config, state);
+}
+
+/// Connect an event listener, and forward its events to the main loop from a separate thread, so
+/// deferred updates can be applied on timeout. Each listener bumps the shared generation and tags
+/// its events with it, so once replaced its thread stops forwarding, and anything it already sent
+/// can be told apart
+fn listen(
+    retries: u32,
+    subs: &[Subscription],
+    sender: Sender<Message>,
+    generation: &Arc<AtomicUsize>,
+) -> Result<(), Box<dyn Error>> {
+    let mut listener = connect_with_retry(retries, I3EventListener::connect)?;
+    listener.subscribe(subs)?;
+    let current = Arc::clone(generation);
+    let own = current.fetch_add(1, Ordering::SeqCst) + 1;
+    thread::spawn(move || {
+        for event in listener.listen() {
+            if current.load(Ordering::SeqCst) != own
+                || sender.send(Message::Event(own, event)).is_err()
+            {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Check that i3 is still reachable with a cheap request, reconnecting if the connection is dead,
+/// and subscribe again with a new listener replacing the old one, which can be dead or stalled
+/// even while i3 answers requests
+fn probe(
+    mut i3_conn: I3Connection,
+    retries: u32,
+    subs: &[Subscription],
+    sender: &Sender<Message>,
+    generation: &Arc<AtomicUsize>,
+) -> Result<I3Connection, Box<dyn Error>> {
+    if let Err(error) = i3_conn.get_workspaces() {
+        eprintln!("Watchdog: lost connection to i3: {}, reconnecting", error);
+        i3_conn = connect_with_retry(retries, I3Connection::connect)?;
+    }
+    listen(retries, subs, sender.clone(), generation)?;
+    Ok(i3_conn)
+}
+
+/// Listen for termination signals, and reset workspace names before exiting
+fn reset_on_exit(config: Config, res: Compiled) -> Result<(), Box<dyn Error>> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            match I3Connection::connect() {
+                Ok(mut i3_conn) => {
+                    if let Err(error) = i3wsr::reset_tree(&mut i3_conn, &config, &res) {
+                        eprintln!("reset_tree error: {}", error);
+                    }
+                }
+                Err(error) => eprintln!("Failed to connect to i3 on exit: {}", error),
+            }
+            std::process::exit(128 + signal);
+        }
+    });
+    Ok(())
+}
+
+/// Fill in defaults for a preset, without overriding values set in the config
+fn apply_preset(config: &mut Config, preset: Preset) {
+    let (general, options): (&[(&str, &str)], &[&str]) = match preset {
+        // polybar's strip-wsnumbers strips everything up to and including the first ':'
+        Preset::Polybar => (&[("split_at", ":")], &[]),
+        // Font Awesome's window icon for windows without one of their own
+        Preset::IconsOnly => (
+            &[("separator", " "), ("default_icon", "\u{f2d0}")],
+            &["no_icon_names"],
+        ),
+    };
+    for (key, value) in general {
+        config
+            .general
+            .entry(key.to_string())
+            .or_insert_with(|| value.to_string());
+    }
+    for key in options {
+        config.options.entry(key.to_string()).or_insert(true);
+    }
 }
 
 /// Setup program by handling args and populating config
-/// Returns result containing config
-fn setup() -> Result<Config, Box<dyn Error>> {
+/// Returns result containing config and args
+fn setup() -> Result<(Config, Args), Box<dyn Error>> {
     let args = Args::parse();
+    // Keep stdout to the report alone when printing or checking the config, so it can be redirected
+    let quiet = args.quiet || args.print_config || args.check_config;
+    i3wsr::QUIET.store(quiet, Ordering::Relaxed);
 
     // icons
     // Not really that useful this opt but keeping for posterity
@@ -64,27 +269,52 @@ fn setup() -> Result<Config, Box<dyn Error>> {
         None => "",
     };
 
-    // handle config
-    let xdg_config = config_dir().unwrap().join("i3wsr/config.toml");
-    let config_result = match args.config.as_deref() {
-        Some(filename) => {
-            println!("{filename}");
-            Config::new(Path::new(filename), icons)
-        }
-        None => {
-            if (xdg_config).exists() {
-                Config::new(&xdg_config, icons)
-            } else {
-                Ok(Config {
-                    icons: i3wsr::icons::get_icons(icons),
-                    ..Default::default()
-                })
-            }
-        }
+    // handle config, an explicit path (from --config or $I3WSR_CONFIG) must exist, the XDG default
+    // is optional
+    let config_path = match args.config.as_deref() {
+        Some(filename) => PathBuf::from(filename),
+        None => config_dir()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Could not determine the config directory, set $XDG_CONFIG_HOME or $HOME, \
+                     or pass a config file with --config or $I3WSR_CONFIG",
+                )
+            })?
+            .join("i3wsr/config.toml"),
+    };
+    let config_result = if args.config.is_some() || config_path.exists() {
+        info(&format!("Loading config from: {}", config_path.display()));
+        Config::new(&config_path, icons)
+    } else {
+        Ok(Config {
+            icons: i3wsr::icons::get_icons(icons),
+            ..Default::default()
+        })
     };
 
     let mut config = config_result?;
 
+    // Merge fragments from config.d next to the config file, in lexical order
+    if let Some(fragments) = config_path.parent().map(|dir| dir.join("config.d")) {
+        if fragments.is_dir() {
+            info(&format!("Loading config fragments from: {}", fragments.display()));
+            config.merge_dir(&fragments)?;
+        }
+    }
+    config.merge_preset()?;
+    if let Some(profile) = &args.profile {
+        config.apply_profile(profile)?;
+    }
+    if let Some(preset) = args.preset {
+        apply_preset(&mut config, preset);
+    }
+    config.validate()?;
+
+    // Verbose can be set persistently in the config, or per run with the flag
+    let verbose = config.options.get("verbose").copied().unwrap_or(false);
+    i3wsr::VERBOSE.store(args.verbose || verbose, Ordering::Relaxed);
+
     // Flags
     if args.no_icon_names {
         config
@@ -102,8 +332,10 @@ fn setup() -> Result<Config, Box<dyn Error>> {
             .insert("remove_duplicates".to_string(), args.remove_duplicates);
     }
 
-    if let Some(split_char) = args.split_at {
-        config.general.insert("split_at".to_string(), split_char);
+    if let Some(split_char) = &args.split_at {
+        config
+            .general
+            .insert("split_at".to_string(), split_char.to_owned());
     }
 
     // wm property
@@ -118,32 +350,179 @@ fn setup() -> Result<Config, Box<dyn Error>> {
     config
         .general
         .insert("display_property".to_string(), display_property);
-    Ok(config)
+    Ok((config, args))
 }
 
 /// Entry main loop: continusly listen to i3 window events and workspace events, or exit on
 /// abnormal error.
 fn main() -> Result<(), Box<dyn Error>> {
-    let config = setup()?;
+    let (config, args) = setup()?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    if args.check_config {
+        i3wsr::regex::parse_config(&config)?;
+        let warnings = config.warnings();
+        for warning in &warnings {
+            println!("Warning: {}", warning);
+        }
+        println!("Config OK, {} warning(s)", warnings.len());
+        return Ok(());
+    }
     let res = i3wsr::regex::parse_config(&config)?;
-    let mut listener = I3EventListener::connect()?;
-    let subs = [Subscription::Window, Subscription::Workspace];
+    if args.dry_run {
+        let mut i3_conn = connect_with_retry(args.startup_retries, I3Connection::connect)?;
+        let proposals = i3wsr::plan(&mut i3_conn, &config, &res)?;
+        if args.report {
+            println!("{}", i3wsr::format_report(&proposals));
+        } else {
+            for proposal in proposals.iter().filter(|p| p.changed()) {
+                println!("\"{}\" -> \"{}\"", proposal.old, proposal.new);
+            }
+        }
+        return Ok(());
+    }
+    let mut subs = vec![Subscription::Window, Subscription::Workspace];
+    if config.options.get("output_events").copied().unwrap_or(false) {
+        subs.push(Subscription::Output);
+    }
 
-    listener.subscribe(&subs)?;
+    let (sender, receiver) = mpsc::channel();
+    let generation = Arc::new(AtomicUsize::new(0));
+    listen(args.startup_retries, &subs, sender.clone(), &generation)?;
 
-    let mut i3_conn = I3Connection::connect()?;
-    i3wsr::update_tree(&mut i3_conn, &config, &res)?;
+    let mut i3_conn = connect_with_retry(args.startup_retries, I3Connection::connect)?;
+    if args.reset_on_exit {
+        reset_on_exit(config.clone(), res.clone())?;
+    }
+    let mut state = State::default();
+    i3wsr::update_tree(&mut i3_conn, &config, &res, &mut state)?;
+    if state.mostly_failed() {
+        eprintln!(
+            "Warning: no title found for {} of {} windows, check that display_property is \
+             correct, and run with --verbose for details",
+            state.title_failures, state.windows
+        );
+    }
+    info("Started successfully, listening for events");
+
+    // Print a summary on SIGUSR1
+    let mut signals = Signals::new([SIGUSR1])?;
+    let summary_sender = sender.clone();
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if summary_sender.send(Message::Summary).is_err() {
+                break;
+            }
+        }
+    });
 
-    for event in listener.listen() {
-        match event? {
+    let health_interval = args.health_interval.map(Duration::from_secs);
+    let mut next_health = health_interval.map(|interval| Instant::now() + interval);
+    let watchdog = args.watchdog.map(Duration::from_secs);
+    let mut last_event = Instant::now();
+    loop {
+        // Wake up for whichever comes first, a deferred update, a health report, or the watchdog
+        let next_watchdog = watchdog.map(|interval| last_event + interval);
+        let wakeup = [state.deferred, next_health, next_watchdog]
+            .into_iter()
+            .flatten()
+            .min();
+        let message = match wakeup {
+            Some(wakeup) => {
+                match receiver.recv_timeout(wakeup.saturating_duration_since(Instant::now())) {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => {
+                        let now = Instant::now();
+                        if state.deferred.map_or(false, |deferred| deferred <= now) {
+                            state.deferred = None;
+                            if let Err(error) =
+                                i3wsr::update_tree(&mut i3_conn, &config, &res, &mut state)
+                            {
+                                report("update_tree", &error, &config, &mut state);
+                            }
+                        }
+                        if let (Some(interval), Some(health)) = (health_interval, next_health) {
+                            if health <= now {
+                                println!("{}", state.health());
+                                next_health = Some(now + interval);
+                            }
+                        }
+                        if next_watchdog.map_or(false, |next| next <= now) {
+                            last_event = now;
+                            i3_conn = probe(
+                                i3_conn,
+                                args.startup_retries,
+                                &subs,
+                                &sender,
+                                &generation,
+                            )?;
+                            if let Err(error) =
+                                i3wsr::update_tree(&mut i3_conn, &config, &res, &mut state)
+                            {
+                                report("update_tree", &error, &config, &mut state);
+                            }
+                        }
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match receiver.recv() {
+                Ok(message) => message,
+                Err(_) => break,
+            },
+        };
+        let event = match message {
+            // Left over from a replaced listener
+            Message::Event(from, _) if from != generation.load(Ordering::SeqCst) => continue,
+            Message::Event(_, event) => {
+                last_event = Instant::now();
+                event
+            }
+            Message::Summary => {
+                println!("{}", state.summary());
+                continue;
+            }
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(error) => {
+                report("event listener", &error, &config, &mut state);
+                // With the watchdog on, a failed listener is replaced rather than exiting
+                if watchdog.is_none() {
+                    Err(error)?
+                }
+                i3_conn = probe(i3_conn, args.startup_retries, &subs, &sender, &generation)?;
+                if let Err(error) = i3wsr::update_tree(&mut i3_conn, &config, &res, &mut state) {
+                    report("update_tree", &error, &config, &mut state);
+                }
+                continue;
+            }
+        };
+        match event {
             Event::WindowEvent(e) => {
-                if let Err(error) = i3wsr::handle_window_event(&e, &mut i3_conn, &config, &res) {
-                    eprintln!("handle_window_event error: {}", error);
+                state.window_events += 1;
+                if let Err(error) =
+                    i3wsr::handle_window_event(&e, &mut i3_conn, &config, &res, &mut state)
+                {
+                    report("handle_window_event", &error, &config, &mut state);
                 }
             }
             Event::WorkspaceEvent(e) => {
-                if let Err(error) = i3wsr::handle_ws_event(&e, &mut i3_conn, &config, &res) {
-                    eprintln!("handle_ws_event error: {}", error);
+                state.workspace_events += 1;
+                if let Err(error) =
+                    i3wsr::handle_ws_event(&e, &mut i3_conn, &config, &res, &mut state)
+                {
+                    report("handle_ws_event", &error, &config, &mut state);
+                }
+            }
+            Event::OutputEvent(_) => {
+                if let Err(error) =
+                    i3wsr::handle_output_event(&mut i3_conn, &config, &res, &mut state)
+                {
+                    report("handle_output_event", &error, &config, &mut state);
                 }
             }
             _ => {}
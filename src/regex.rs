@@ -1,12 +1,26 @@
 use crate::Config;
+use itertools::Itertools;
 use regex::Regex;
+use std::collections::HashMap;
 use std::error::Error;
 
 pub type Point = (Regex, String);
+
+/// Reserved alias key matching anything, only applied after all patterns fail
+const CATCH_ALL: &str = "*";
+
+#[derive(Clone)]
 pub struct Compiled {
     pub class: Vec<Point>,
     pub instance: Vec<Point>,
     pub name: Vec<Point>,
+    pub composite: Vec<Point>,
+    pub catch_all: Option<String>,
+    pub dialog: Vec<Regex>,
+    pub ignore_titles: Vec<Regex>,
+    pub exclude_outputs: Vec<Regex>,
+    pub number: Option<Regex>,
+    pub icons: Vec<(Regex, char)>,
 }
 
 fn compile((k, v): (&String, &String)) -> Result<Point, Box<dyn Error>> {
@@ -14,22 +28,59 @@ fn compile((k, v): (&String, &String)) -> Result<Point, Box<dyn Error>> {
     Ok((re, v.to_owned()))
 }
 
+/// Compile alias patterns, leaving out the catch-all which is matched separately
+fn compile_aliases(aliases: &HashMap<String, String>) -> Result<Vec<Point>, Box<dyn Error>> {
+    aliases
+        .iter()
+        .filter(|(k, _)| k.as_str() != CATCH_ALL)
+        .map(compile)
+        .collect()
+}
+
+/// Compile a list of patterns from the patterns section
+fn compile_list(config: &Config, key: &str) -> Result<Vec<Regex>, Box<dyn Error>> {
+    match config.patterns.get(key) {
+        Some(list) => Ok(list
+            .iter()
+            .map(|p| Regex::new(p))
+            .collect::<Result<Vec<Regex>, _>>()?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Compile icon keys as patterns when using regex icons, sorted by pattern so that multiple
+/// matching icons have a stable order
+fn compile_icons(config: &Config) -> Result<Vec<(Regex, char)>, Box<dyn Error>> {
+    if !config.options.get("regex_icons").copied().unwrap_or(false) {
+        return Ok(Vec::new());
+    }
+    config
+        .icons
+        .iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(k, v)| -> Result<(Regex, char), Box<dyn Error>> { Ok((Regex::new(k)?, *v)) })
+        .collect()
+}
+
 pub fn parse_config(config: &Config) -> Result<Compiled, Box<dyn Error>> {
-    let classes = match config.aliases.class.iter().map(compile).collect() {
-        Ok(v) => v,
-        Err(e) => Err(e)?,
-    };
-    let instances = match config.aliases.instance.iter().map(compile).collect() {
-        Ok(v) => v,
-        Err(e) => Err(e)?,
-    };
-    let names = match config.aliases.name.iter().map(compile).collect() {
-        Ok(v) => v,
-        Err(e) => Err(e)?,
-    };
+    let classes = compile_aliases(&config.aliases.class)?;
+    let instances = compile_aliases(&config.aliases.instance)?;
+    let names = compile_aliases(&config.aliases.name)?;
     return Ok(Compiled {
         class: classes,
         instance: instances,
         name: names,
+        composite: compile_aliases(&config.aliases.composite)?,
+        catch_all: [&config.aliases.class, &config.aliases.instance, &config.aliases.name]
+            .iter()
+            .find_map(|aliases| aliases.get(CATCH_ALL).cloned()),
+        dialog: compile_list(config, "dialog_classes")?,
+        ignore_titles: compile_list(config, "ignore_titles")?,
+        exclude_outputs: compile_list(config, "exclude_outputs")?,
+        icons: compile_icons(config)?,
+        number: match config.general.get("number_regex") {
+            Some(re) => Some(Regex::new(re)?),
+            None => None,
+        },
     });
 }
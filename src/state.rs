@@ -0,0 +1,99 @@
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// State kept between updates
+#[derive(Default)]
+pub struct State {
+    /// Time of the last rename, per workspace id
+    pub last_rename: HashMap<i64, Instant>,
+    /// Recent renames, per workspace id, used to detect rename loops
+    pub recent_renames: HashMap<i64, VecDeque<(Instant, String)>>,
+    /// Time until which renames are suppressed after a loop was detected, per workspace id
+    pub cooldown: HashMap<i64, Instant>,
+    /// Ids of windows that were hidden to the scratchpad
+    pub scratchpad: HashSet<i64>,
+    /// Time a workspace was found emptied, while its previous name lingers
+    pub emptied: HashMap<i64, Instant>,
+    /// Id of the previously focused workspace
+    pub previous: Option<i64>,
+    /// Whether exceeding `max_workspaces` was warned about, until back under the limit
+    pub limit_warned: bool,
+    /// Time of the last error notification
+    pub last_notify: Option<Instant>,
+    /// Time at which a deferred update should be applied
+    pub deferred: Option<Instant>,
+    /// Number of window events handled
+    pub window_events: usize,
+    /// Number of workspace events handled
+    pub workspace_events: usize,
+    /// Number of renames issued
+    pub renames: usize,
+    /// Number of windows seen
+    pub windows: usize,
+    /// Number of windows where no title was found
+    pub title_failures: usize,
+    /// Current workspace names, per workspace id
+    pub names: HashMap<i64, String>,
+    /// Events, renames and title failures at the time of the last health report
+    reported: (usize, usize, usize),
+}
+
+impl State {
+    /// Defer an update until given time, keeping the earliest
+    pub fn defer(&mut self, until: Instant) {
+        self.deferred = Some(match self.deferred {
+            Some(deferred) => deferred.min(until),
+            None => until,
+        });
+    }
+
+    /// Postpone a deferred update until given time, keeping the latest
+    pub fn postpone(&mut self, until: Instant) {
+        self.deferred = Some(match self.deferred {
+            Some(deferred) => deferred.max(until),
+            None => until,
+        });
+    }
+
+    /// Record a rename, and check if the workspace was renamed more than limit times within
+    /// window, back to a name it already had, which means renames are triggering each other
+    pub fn record_rename(&mut self, id: i64, name: &str, window: Duration, limit: usize) -> bool {
+        let now = Instant::now();
+        let recent = self.recent_renames.entry(id).or_default();
+        recent.retain(|(at, _)| now.duration_since(*at) <= window);
+        recent.push_back((now, name.to_string()));
+        recent.len() > limit && recent.iter().map(|(_, name)| name).duplicates().next().is_some()
+    }
+
+    /// Check if at least half of the windows seen had no title found
+    pub fn mostly_failed(&self) -> bool {
+        self.windows > 0 && self.title_failures * 2 >= self.windows
+    }
+
+    /// Report of events, renames and title failures since the last report
+    pub fn health(&mut self) -> String {
+        let (events, renames, failures) = self.reported;
+        let total = self.window_events + self.workspace_events;
+        let report = format!(
+            "health: {} events, {} renames, {} title failures since last report",
+            total - events,
+            self.renames - renames,
+            self.title_failures - failures
+        );
+        self.reported = (total, self.renames, self.title_failures);
+        report
+    }
+
+    /// Summary of counters, and current workspace names
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "window events: {}, workspace events: {}, renames: {}, title failures: {}",
+            self.window_events, self.workspace_events, self.renames, self.title_failures
+        );
+        for (id, name) in self.names.iter().sorted_by_key(|(id, _)| **id) {
+            summary.push_str(&format!("\n{}: {}", id, name));
+        }
+        summary
+    }
+}